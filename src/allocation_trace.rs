@@ -0,0 +1,256 @@
+use core::cell::Cell;
+use core::cmp::Reverse;
+use core::ptr::null_mut;
+
+use wasm_bindgen::prelude::*;
+
+// One live allocation. A ptr of 0 marks an empty slot of the table.
+#[derive(Copy, Clone)]
+pub(crate) struct TraceRecord {
+    pub(crate) ptr: usize,
+    pub(crate) size: usize,
+    #[allow(dead_code)]
+    pub(crate) align: usize,
+    pub(crate) tag: &'static str,
+    pub(crate) frame: usize,
+}
+
+impl TraceRecord {
+    const EMPTY: TraceRecord = TraceRecord {
+        ptr: 0,
+        size: 0,
+        align: 0,
+        tag: "",
+        frame: 0,
+    };
+}
+
+// Fixed capacity hash table of live allocations, keyed by address. Open addressing with linear
+// probing, and backward shift deletion so that no tombstones are needed.
+//
+// The table lives in a region handed over by its WasmAllocator, and never allocates, so it can be
+// updated from inside GlobalAlloc. Records which do not fit are counted as dropped.
+pub(crate) struct TraceTable {
+    records: Cell<*mut TraceRecord>,
+    // Always a power of two, or 0 before a region is set
+    capacity: Cell<usize>,
+    live: Cell<usize>,
+    dropped: Cell<usize>,
+    enabled: Cell<bool>,
+}
+
+impl TraceTable {
+    pub(crate) const fn new() -> Self {
+        TraceTable {
+            records: Cell::new(null_mut()),
+            capacity: Cell::new(0),
+            live: Cell::new(0),
+            dropped: Cell::new(0),
+            enabled: Cell::new(false),
+        }
+    }
+
+    // Rounds a requested number of records up to a valid table capacity. One slot always stays
+    // empty, which ends every probe sequence. None when the capacity does not fit in a usize.
+    pub(crate) fn table_capacity(records: usize) -> Option<usize> {
+        records.checked_add(1)?.checked_next_power_of_two()
+    }
+
+    // 'records' must point to table_capacity() writable records.
+    pub(crate) unsafe fn set_region(&self, records: *mut TraceRecord, capacity: usize) {
+        for slot in 0..capacity {
+            records.add(slot).write(TraceRecord::EMPTY);
+        }
+
+        self.records.set(records);
+        self.capacity.set(capacity);
+        self.live.set(0);
+        self.dropped.set(0);
+    }
+
+    pub(crate) fn has_region(&self) -> bool {
+        !self.records.get().is_null()
+    }
+
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        self.enabled.set(enabled);
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    // Records an allocation, when tracing is enabled.
+    pub(crate) fn insert(&self, record: TraceRecord) {
+        if !self.enabled.get() {
+            return;
+        }
+
+        if self.live.get() + 1 >= self.capacity.get() {
+            self.dropped.set(self.dropped.get() + 1);
+            return;
+        }
+
+        let mut slot = self.home_slot(record.ptr);
+        unsafe {
+            while (*self.records.get().add(slot)).ptr != 0 {
+                slot = (slot + 1) & (self.capacity.get() - 1);
+            }
+            self.records.get().add(slot).write(record);
+        }
+        self.live.set(self.live.get() + 1);
+    }
+
+    // Updates the size of an allocation resized in place.
+    pub(crate) fn update_size(&self, ptr: usize, size: usize) {
+        if let Some(slot) = self.find(ptr) {
+            unsafe {
+                (*self.records.get().add(slot)).size = size;
+            }
+        }
+    }
+
+    // Forgets an allocation. Allocations made while tracing was disabled are simply not found.
+    pub(crate) fn remove(&self, ptr: usize) {
+        let slot = match self.find(ptr) {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let mask = self.capacity.get() - 1;
+        let records = self.records.get();
+
+        unsafe {
+            // Backward shift: pull later records of the probe sequence into the hole, unless that
+            // would move them before their home slot.
+            let mut hole = slot;
+            let mut next = (hole + 1) & mask;
+            while (*records.add(next)).ptr != 0 {
+                let home = self.home_slot((*records.add(next)).ptr);
+                if (next.wrapping_sub(home) & mask) >= (next.wrapping_sub(hole) & mask) {
+                    records.add(hole).write(*records.add(next));
+                    hole = next;
+                }
+                next = (next + 1) & mask;
+            }
+            records.add(hole).write(TraceRecord::EMPTY);
+        }
+
+        self.live.set(self.live.get() - 1);
+    }
+
+    // Returns the slot holding the record of an allocation.
+    fn find(&self, ptr: usize) -> Option<usize> {
+        if !self.has_region() {
+            return None;
+        }
+
+        let mask = self.capacity.get() - 1;
+        let mut slot = self.home_slot(ptr);
+
+        loop {
+            let current = unsafe { (*self.records.get().add(slot)).ptr };
+            if current == 0 {
+                return None;
+            }
+            if current == ptr {
+                return Some(slot);
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+
+    pub(crate) fn records(&self) -> impl Iterator<Item = TraceRecord> + '_ {
+        (0..self.capacity.get())
+            .map(move |slot| unsafe { *self.records.get().add(slot) })
+            .filter(|record| record.ptr != 0)
+    }
+
+    // Groups the live allocations by tag, largest total first. Allocates the report, so tracing
+    // should be paused while this runs (see WasmAllocator::trace_report()).
+    pub(crate) fn report(&self) -> TraceReport {
+        let mut groups: Vec<TraceGroup> = Vec::new();
+
+        for record in self.records() {
+            match groups.iter_mut().find(|group| group.tag == record.tag) {
+                Some(group) => {
+                    group.allocations += 1;
+                    group.bytes += record.size;
+                    group.oldest_frame = group.oldest_frame.min(record.frame);
+                    group.newest_frame = group.newest_frame.max(record.frame);
+                }
+                None => groups.push(TraceGroup {
+                    tag: record.tag,
+                    allocations: 1,
+                    bytes: record.size,
+                    oldest_frame: record.frame,
+                    newest_frame: record.frame,
+                }),
+            }
+        }
+
+        groups.sort_by_key(|group| Reverse(group.bytes));
+
+        TraceReport {
+            live_allocations: self.live.get(),
+            live_bytes: groups.iter().map(|group| group.bytes).sum(),
+            dropped_records: self.dropped.get(),
+            groups,
+        }
+    }
+
+    fn home_slot(&self, ptr: usize) -> usize {
+        // Fibonacci hashing of the address, keeping the well mixed high bits of the product
+        let hash = ((ptr as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize;
+        hash & (self.capacity.get() - 1)
+    }
+}
+
+// Live allocations sharing a tag.
+#[derive(Clone, Debug)]
+pub(crate) struct TraceGroup {
+    pub(crate) tag: &'static str,
+    pub(crate) allocations: usize,
+    pub(crate) bytes: usize,
+    pub(crate) oldest_frame: usize,
+    pub(crate) newest_frame: usize,
+}
+
+// Live allocations recorded by a WasmAllocator while tracing, grouped by tag. Exported to
+// JavaScript through allocation_report() in lib.rs. Allocations which outlive a level load
+// show up as groups whose oldest_frame predates it.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default)]
+pub struct TraceReport {
+    pub live_allocations: usize,
+    pub live_bytes: usize,
+    pub dropped_records: usize,
+    pub(crate) groups: Vec<TraceGroup>,
+}
+
+#[wasm_bindgen]
+impl TraceReport {
+    pub fn group_count(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn group_tag(&self, group: usize) -> Option<String> {
+        self.groups.get(group).map(|group| group.tag.to_string())
+    }
+
+    pub fn group_allocations(&self, group: usize) -> Option<usize> {
+        self.groups.get(group).map(|group| group.allocations)
+    }
+
+    pub fn group_bytes(&self, group: usize) -> Option<usize> {
+        self.groups.get(group).map(|group| group.bytes)
+    }
+
+    pub fn group_oldest_frame(&self, group: usize) -> Option<usize> {
+        self.groups.get(group).map(|group| group.oldest_frame)
+    }
+
+    pub fn group_newest_frame(&self, group: usize) -> Option<usize> {
+        self.groups.get(group).map(|group| group.newest_frame)
+    }
+}
@@ -0,0 +1,191 @@
+use core::cell::Cell;
+use core::mem::{align_of, size_of};
+use core::ptr::null_mut;
+use std::alloc::Layout;
+
+use crate::error::CoreError;
+
+// Written before and after every block. Overruns (and underruns) of up to CANARY_SIZE bytes are
+// guaranteed to land in them.
+pub(crate) const CANARY: u8 = 0xCA;
+pub(crate) const CANARY_SIZE: usize = 16;
+
+// Written over every block when it is freed, so a dangling pointer reads an obvious pattern.
+pub(crate) const POISON: u8 = 0xDE;
+
+// Stored at the start of every guarded block, followed by the front canary, the caller's bytes
+// and the back canary:
+//
+// | GuardHeader | front canary (>= CANARY_SIZE) | block (layout.size()) | back canary |
+//
+// The front canary is padded so the block keeps the alignment requested by the caller.
+struct GuardHeader {
+    origin: &'static str,
+    serial: usize,
+    layout: Layout,
+    previous: *mut GuardHeader,
+    next: *mut GuardHeader,
+}
+
+// Where a corrupted block came from.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct GuardFailure {
+    pub(crate) origin: &'static str,
+    pub(crate) serial: usize,
+    pub(crate) size: usize,
+}
+
+impl From<GuardFailure> for CoreError {
+    fn from(failure: GuardFailure) -> Self {
+        CoreError::GuardCorrupted {
+            origin: failure.origin,
+            serial: failure.serial,
+            size: failure.size,
+        }
+    }
+}
+
+// The live guarded blocks of one allocator, as a doubly linked list threaded through their
+// headers. Nothing here allocates, so it is safe to use from GlobalAlloc.
+pub(crate) struct GuardList {
+    head: Cell<*mut GuardHeader>,
+    serial: Cell<usize>,
+    // Corruption found while freeing cannot be logged from inside GlobalAlloc. It is kept here
+    // until the next check().
+    first_failure: Cell<Option<GuardFailure>>,
+}
+
+impl GuardList {
+    pub(crate) const fn new() -> Self {
+        GuardList {
+            head: Cell::new(null_mut()),
+            serial: Cell::new(0),
+            first_failure: Cell::new(None),
+        }
+    }
+
+    // The layout to allocate for a guarded block holding 'layout'.
+    pub(crate) fn guarded_layout(layout: Layout) -> Layout {
+        let size = Self::front_size(layout) + layout.size() + CANARY_SIZE;
+        let align = layout.align().max(align_of::<GuardHeader>());
+
+        unsafe { Layout::from_size_align_unchecked(size, align) }
+    }
+
+    // Fills the header and the canaries of a freshly allocated guarded block, and returns the
+    // pointer handed to the caller.
+    pub(crate) unsafe fn arm(
+        &self,
+        base: *mut u8,
+        layout: Layout,
+        origin: &'static str,
+    ) -> *mut u8 {
+        let front_size = Self::front_size(layout);
+        let ptr = base.add(front_size);
+
+        let serial = self.serial.get() + 1;
+        self.serial.set(serial);
+
+        let header = base as *mut GuardHeader;
+        header.write(GuardHeader {
+            origin,
+            serial,
+            layout,
+            previous: null_mut(),
+            next: self.head.get(),
+        });
+        if !self.head.get().is_null() {
+            (*self.head.get()).previous = header;
+        }
+        self.head.set(header);
+
+        let canary_start = base.add(size_of::<GuardHeader>());
+        canary_start.write_bytes(CANARY, ptr as usize - canary_start as usize);
+        ptr.add(layout.size()).write_bytes(CANARY, CANARY_SIZE);
+
+        ptr
+    }
+
+    // Checks the canaries of a block being freed, unlinks it and poisons it. Returns the base
+    // pointer the guarded block was allocated at.
+    pub(crate) unsafe fn disarm(&self, ptr: *mut u8, layout: Layout) -> *mut u8 {
+        let base = ptr.sub(Self::front_size(layout));
+        let header = base as *mut GuardHeader;
+
+        if let Err(failure) = Self::check_block(header) {
+            if self.first_failure.get().is_none() {
+                self.first_failure.set(Some(failure));
+            }
+        }
+
+        let previous = (*header).previous;
+        let next = (*header).next;
+        if previous.is_null() {
+            self.head.set(next);
+        } else {
+            (*previous).next = next;
+        }
+        if !next.is_null() {
+            (*next).previous = previous;
+        }
+
+        base.write_bytes(POISON, Self::guarded_layout(layout).size());
+        base
+    }
+
+    // Reports the first corruption found while freeing, if any, then checks every live block.
+    pub(crate) fn check(&self) -> Result<(), CoreError> {
+        if let Some(failure) = self.first_failure.take() {
+            return Err(failure.into());
+        }
+
+        let mut header = self.head.get();
+        while !header.is_null() {
+            unsafe {
+                Self::check_block(header)?;
+                header = (*header).next;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Number of guarded blocks not yet freed.
+    #[allow(dead_code)]
+    pub(crate) fn live_blocks(&self) -> usize {
+        let mut count = 0;
+        let mut header = self.head.get();
+        while !header.is_null() {
+            count += 1;
+            header = unsafe { (*header).next };
+        }
+        count
+    }
+
+    unsafe fn check_block(header: *mut GuardHeader) -> Result<(), GuardFailure> {
+        let layout = (*header).layout;
+        let base = header as *mut u8;
+        let ptr = base.add(Self::front_size(layout));
+
+        let front = base.add(size_of::<GuardHeader>());
+        let front_size = ptr as usize - front as usize;
+        let back = ptr.add(layout.size());
+
+        let intact = (0..front_size).all(|offset| *front.add(offset) == CANARY)
+            && (0..CANARY_SIZE).all(|offset| *back.add(offset) == CANARY);
+
+        if intact {
+            return Ok(());
+        }
+
+        Err(GuardFailure {
+            origin: (*header).origin,
+            serial: (*header).serial,
+            size: layout.size(),
+        })
+    }
+
+    fn front_size(layout: Layout) -> usize {
+        (size_of::<GuardHeader>() + CANARY_SIZE).next_multiple_of(layout.align())
+    }
+}
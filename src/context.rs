@@ -1,2 +1,3 @@
 // TODO: Find more specific name (WebContext?)
+#[allow(dead_code)]
 pub struct Context {}
@@ -0,0 +1,120 @@
+use core::fmt;
+use std::error::Error;
+
+use wasm_bindgen::JsValue;
+
+use crate::indexing::IndexError;
+
+// Crate wide error type, returned by the try_ variants of functions which otherwise log through
+// console_log!() and panic!(). Converts into a JsValue so it can be propagated out of
+// #[wasm_bindgen] functions with '?'.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub(crate) enum CoreError {
+    Index(IndexError),
+    OutOfMemory {
+        pages: usize,
+    },
+    ArenaExhausted {
+        requested: usize,
+        available: usize,
+    },
+    InvalidLayout {
+        size: usize,
+        align: usize,
+    },
+    OutOfOrderRollback {
+        depth: usize,
+        innermost: usize,
+    },
+    GuardCorrupted {
+        origin: &'static str,
+        serial: usize,
+        size: usize,
+    },
+    UnknownStore {
+        store: usize,
+    },
+    GrowthLocked {
+        views: usize,
+    },
+    Uninitialized,
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexError::UsizeDowncastError => write!(f, "usize does not fit within the index type"),
+            IndexError::GenerationOverflowError => {
+                write!(f, "generation does not fit within the generation bits")
+            }
+            IndexError::ZeroIndexError => write!(f, "index 0 is reserved as empty"),
+        }
+    }
+}
+
+impl Error for IndexError {}
+
+impl fmt::Display for CoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoreError::Index(error) => write!(f, "Index error: {}", error),
+            CoreError::OutOfMemory { pages } => {
+                write!(f, "Out of memory condition (requested {} pages)", pages)
+            }
+            CoreError::ArenaExhausted {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Arena exhausted (requested {} bytes, {} bytes available)",
+                requested, available
+            ),
+            CoreError::InvalidLayout { size, align } => write!(
+                f,
+                "Invalid layout ({} bytes, aligned to {} bytes)",
+                size, align
+            ),
+            CoreError::OutOfOrderRollback { depth, innermost } => write!(
+                f,
+                "Out of order rollback (marker at depth {}, innermost marker at depth {})",
+                depth, innermost
+            ),
+            CoreError::GuardCorrupted {
+                origin,
+                serial,
+                size,
+            } => write!(
+                f,
+                "Guard bytes corrupted around block #{} ({} bytes, from {})",
+                serial, size, origin
+            ),
+            CoreError::UnknownStore { store } => write!(f, "No store numbered {}", store),
+            CoreError::GrowthLocked { views } => {
+                write!(f, "Memory cannot grow while {} raw views are alive", views)
+            }
+            CoreError::Uninitialized => write!(f, "WebCore has not been initialized yet"),
+        }
+    }
+}
+
+impl Error for CoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CoreError::Index(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<IndexError> for CoreError {
+    fn from(error: IndexError) -> Self {
+        CoreError::Index(error)
+    }
+}
+
+impl From<CoreError> for JsValue {
+    fn from(error: CoreError) -> Self {
+        js_sys::Error::new(&error.to_string()).into()
+    }
+}
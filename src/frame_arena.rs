@@ -0,0 +1,153 @@
+use core::cell::Cell;
+use core::ops::{Deref, DerefMut};
+use core::ptr::null_mut;
+use std::alloc::Layout;
+
+use super::{console_log, log};
+use crate::error::CoreError;
+
+// Written over the used part of the region on every reset, in debug builds only.
+pub(crate) const FRAME_POISON: u8 = 0xDF;
+
+// A region of memory for allocations which live for exactly one frame. Allocation bumps
+// frame_ptr through the region, and reset() rewinds it back to the start, which frees everything
+// allocated during the frame at once.
+//
+// Values placed in the region are never dropped, so only Copy types are accepted.
+pub(crate) struct FrameArena {
+    start: Cell<*mut u8>,
+    frame_ptr: Cell<*mut u8>,
+    #[allow(dead_code)]
+    end: Cell<*mut u8>,
+    // Incremented by every reset, so a FramePtr can tell which frame it was allocated in.
+    frame: Cell<usize>,
+}
+
+#[allow(dead_code)]
+impl FrameArena {
+    pub(crate) const fn new() -> Self {
+        FrameArena {
+            start: Cell::new(null_mut()),
+            frame_ptr: Cell::new(null_mut()),
+            end: Cell::new(null_mut()),
+            frame: Cell::new(0),
+        }
+    }
+
+    // Hands a new region to the arena. The previous region must be reset() first, while it can
+    // still be written to: its owner is free to reuse it as soon as this returns.
+    pub(crate) fn set_region(&self, start: *mut u8, size: usize) {
+        self.start.set(start);
+        self.frame_ptr.set(start);
+        self.end.set(start.wrapping_add(size));
+    }
+
+    pub(crate) fn region(&self) -> (*mut u8, usize) {
+        (self.start.get(), self.capacity())
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.end.get() as usize - self.start.get() as usize
+    }
+
+    // Bytes handed out (including alignment padding) since the last reset.
+    pub(crate) fn used(&self) -> usize {
+        self.frame_ptr.get() as usize - self.start.get() as usize
+    }
+
+    pub(crate) fn frame(&self) -> usize {
+        self.frame.get()
+    }
+
+    pub(crate) fn try_alloc_layout(&self, layout: Layout) -> Result<*mut u8, CoreError> {
+        let frame_ptr_usize = self.frame_ptr.get() as usize;
+        let padding = frame_ptr_usize.wrapping_neg() & (layout.align() - 1);
+        let available = self.end.get() as usize - frame_ptr_usize;
+
+        if padding + layout.size() > available {
+            return Err(CoreError::ArenaExhausted {
+                requested: layout.size(),
+                available,
+            });
+        }
+
+        let ptr = (frame_ptr_usize + padding) as *mut u8;
+        self.frame_ptr.set(ptr.wrapping_add(layout.size()));
+        Ok(ptr)
+    }
+
+    pub(crate) fn try_alloc<T: Copy>(&self, value: T) -> Result<FramePtr<'_, T>, CoreError> {
+        let ptr = self.try_alloc_layout(Layout::new::<T>())? as *mut T;
+
+        unsafe {
+            ptr.write(value);
+        }
+
+        Ok(FramePtr {
+            ptr,
+            frame: self.frame(),
+            arena: self,
+        })
+    }
+
+    // Ends the frame in O(1). Debug builds additionally poison the bytes used during the frame,
+    // so a raw pointer kept past the reset reads FRAME_POISON instead of plausible stale data.
+    pub(crate) fn reset(&self) {
+        if cfg!(debug_assertions) && !self.start.get().is_null() {
+            unsafe {
+                self.start.get().write_bytes(FRAME_POISON, self.used());
+            }
+        }
+
+        self.frame_ptr.set(self.start.get());
+        self.frame.set(self.frame.get() + 1);
+    }
+}
+
+// A value allocated from a FrameArena. It must not be used once the frame has been reset, which
+// debug builds check on every access.
+#[allow(dead_code)]
+pub(crate) struct FramePtr<'a, T> {
+    ptr: *mut T,
+    frame: usize,
+    arena: &'a FrameArena,
+}
+
+#[allow(dead_code)]
+impl<'a, T> FramePtr<'a, T> {
+    pub(crate) fn as_ptr(&self) -> *mut T {
+        self.ptr
+    }
+
+    // Returns false once the frame this value was allocated in has ended.
+    pub(crate) fn is_live(&self) -> bool {
+        self.frame == self.arena.frame()
+    }
+
+    fn check_frame(&self) {
+        if cfg!(debug_assertions) && !self.is_live() {
+            console_log!(
+                "[FramePtr] ERROR: Allocated in frame {}, read after the reset (now frame {})",
+                self.frame,
+                self.arena.frame()
+            );
+            panic!();
+        }
+    }
+}
+
+impl<'a, T> Deref for FramePtr<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.check_frame();
+        unsafe { &*self.ptr }
+    }
+}
+
+impl<'a, T> DerefMut for FramePtr<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.check_frame();
+        unsafe { &mut *self.ptr }
+    }
+}
@@ -1,40 +1,158 @@
 use core::convert::TryFrom;
 use core::fmt::Debug;
+use core::num::{NonZeroU16, NonZeroU32, NonZeroU8};
 
 pub(crate) trait UnsignedType: Copy + Debug {
     const MAX_VALUE: usize;
+
+    // Representation of a non zero value, which lets Option<Index<Self>> use zero as None.
+    type NonZero: Copy + Debug;
 }
 
-pub(crate) trait IndexType: Copy + PartialEq<i32> + TryFrom<usize> + Into<usize> {}
+pub(crate) trait IndexType:
+    Copy + PartialEq<i32> + TryFrom<usize, Error = IndexError> + Into<usize>
+{
+}
 
 impl UnsignedType for u8 {
     const MAX_VALUE: usize = 255;
+    type NonZero = NonZeroU8;
 }
 
 impl UnsignedType for u16 {
     const MAX_VALUE: usize = 65_535;
+    type NonZero = NonZeroU16;
 }
 
 impl UnsignedType for u32 {
     const MAX_VALUE: usize = 1_000_000;
+    type NonZero = NonZeroU32;
+}
+
+// A u32 packing a slot index (low 20 bits) and a generation (high 12 bits), so that generational
+// keys cost no more memory than Index<u32>. Used as Index<Generational>.
+//
+// As with every index, the slot index 0 is reserved for 'empty', so the packed value is never
+// zero.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(transparent)]
+pub(crate) struct Generational(NonZeroU32);
+
+impl Generational {
+    pub(crate) const INDEX_BITS: u32 = 20;
+    pub(crate) const GENERATION_BITS: u32 = 12;
+
+    const INDEX_MASK: u32 = (1 << Self::INDEX_BITS) - 1;
+    pub(crate) const MAX_GENERATION: u32 = (1 << Self::GENERATION_BITS) - 1;
+}
+
+impl UnsignedType for Generational {
+    const MAX_VALUE: usize = Generational::INDEX_MASK as usize;
+    type NonZero = Generational;
+}
+
+// Type level mapping from a capacity N to the narrowest UnsignedType able to index it.
+// IndexWidth<BYTES> is selected by index_width(N), see the index_for! macro.
+pub(crate) struct IndexWidth<const BYTES: usize>;
+
+pub(crate) trait SelectIndex {
+    type Type: UnsignedType;
 }
 
+impl SelectIndex for IndexWidth<1> {
+    type Type = u8;
+}
+
+impl SelectIndex for IndexWidth<2> {
+    type Type = u16;
+}
+
+impl SelectIndex for IndexWidth<4> {
+    type Type = u32;
+}
+
+// Returns the byte width of the narrowest UnsignedType satisfying N <= MAX_VALUE + 1.
+pub(crate) const fn index_width(capacity: usize) -> usize {
+    if capacity <= u8::MAX_VALUE + 1 {
+        1
+    } else if capacity <= u16::MAX_VALUE + 1 {
+        2
+    } else if capacity <= u32::MAX_VALUE + 1 {
+        4
+    } else {
+        panic!("KeyVector capacity N exceeds the range of every index type")
+    }
+}
+
+// Index 0 is reserved as 'empty' by every store. Rather than storing it, the stores use
+// Option<Index<T>>, which is the same size as T because of the non zero representation.
 #[derive(Copy, Clone, Debug)]
-pub(crate) struct Index<T: UnsignedType>(pub(crate) T);
+#[repr(transparent)]
+pub(crate) struct Index<T: UnsignedType>(pub(crate) T::NonZero);
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum IndexError {
     UsizeDowncastError,
+    GenerationOverflowError,
+    ZeroIndexError,
 }
 
 impl IndexType for Index<u8> {}
 impl IndexType for Index<u16> {}
 impl IndexType for Index<u32> {}
+impl IndexType for Index<Generational> {}
+
+#[allow(dead_code)]
+impl Index<Generational> {
+    // Checked constructor: fails when the index is zero or does not fit in INDEX_BITS, or the
+    // generation does not fit in GENERATION_BITS.
+    pub(crate) fn new(index: usize, generation: u32) -> Result<Self, IndexError> {
+        if index == 0 {
+            return Err(IndexError::ZeroIndexError);
+        }
+
+        if index > Generational::MAX_VALUE {
+            return Err(IndexError::UsizeDowncastError);
+        }
+
+        if generation > Generational::MAX_GENERATION {
+            return Err(IndexError::GenerationOverflowError);
+        }
+
+        let packed = (generation << Generational::INDEX_BITS) | (index as u32);
+        Ok(Self::from_packed(packed))
+    }
+
+    pub(crate) fn index(&self) -> usize {
+        ((self.0).0.get() & Generational::INDEX_MASK) as usize
+    }
+
+    pub(crate) fn generation(&self) -> u32 {
+        (self.0).0.get() >> Generational::INDEX_BITS
+    }
+
+    // Same slot index, with the generation incremented. Wraps back to zero after MAX_GENERATION.
+    pub(crate) fn next_generation(&self) -> Self {
+        let generation = (self.generation() + 1) & Generational::MAX_GENERATION;
+        let packed = (generation << Generational::INDEX_BITS) | (self.index() as u32);
+        Self::from_packed(packed)
+    }
+
+    // The slot index bits of 'packed' must not be zero
+    fn from_packed(packed: u32) -> Self {
+        match NonZeroU32::new(packed) {
+            Some(non_zero) => Index(Generational(non_zero)),
+            None => unreachable!(),
+        }
+    }
+}
 
 impl PartialEq<i32> for Index<u8> {
+    #[allow(clippy::needless_bool, clippy::needless_return)]
     fn eq(&self, other: &i32) -> bool {
         // Safe to 'upcast' a u8 to i32 because no loss of bit information
-        if (self.0 as i32) == *other {
+        if (self.0.get() as i32) == *other {
             return true;
         } else {
             return false;
@@ -43,9 +161,10 @@ impl PartialEq<i32> for Index<u8> {
 }
 
 impl PartialEq<i32> for Index<u16> {
+    #[allow(clippy::needless_bool, clippy::needless_return)]
     fn eq(&self, other: &i32) -> bool {
         // Safe to 'upcast' a u16 to i32 because no loss of bit information
-        if (self.0 as i32) == *other {
+        if (self.0.get() as i32) == *other {
             return true;
         } else {
             return false;
@@ -54,6 +173,7 @@ impl PartialEq<i32> for Index<u16> {
 }
 
 impl PartialEq<i32> for Index<u32> {
+    #[allow(clippy::needless_bool, clippy::needless_return)]
     fn eq(&self, other: &i32) -> bool {
         // When the i32 value 'other' is negative, it cannot equal a u32.
         // Handle this condition first.
@@ -62,7 +182,7 @@ impl PartialEq<i32> for Index<u32> {
         }
 
         // When the i32 value 'other' is positive, or zero, we can safely 'upcast' it to a u32.
-        if self.0 == (*other as u32) {
+        if self.0.get() == (*other as u32) {
             return true;
         } else {
             return false;
@@ -70,14 +190,29 @@ impl PartialEq<i32> for Index<u32> {
     }
 }
 
+impl PartialEq<i32> for Index<Generational> {
+    #[allow(clippy::needless_return)]
+    fn eq(&self, other: &i32) -> bool {
+        // Only the slot index takes part in the comparison, matching the Into<usize> conversion.
+        if *other < 0 {
+            return false;
+        }
+
+        return self.index() == (*other as usize);
+    }
+}
+
 impl TryFrom<usize> for Index<u8> {
     type Error = IndexError;
 
     fn try_from(value: usize) -> Result<Self, Self::Error> {
         if value > u8::MAX_VALUE {
-            Err(IndexError::UsizeDowncastError)
-        } else {
-            Ok(Index(value as u8))
+            return Err(IndexError::UsizeDowncastError);
+        }
+
+        match NonZeroU8::new(value as u8) {
+            Some(non_zero) => Ok(Index(non_zero)),
+            None => Err(IndexError::ZeroIndexError),
         }
     }
 }
@@ -87,9 +222,12 @@ impl TryFrom<usize> for Index<u16> {
 
     fn try_from(value: usize) -> Result<Self, Self::Error> {
         if value > u16::MAX_VALUE {
-            Err(IndexError::UsizeDowncastError)
-        } else {
-            Ok(Index(value as u16))
+            return Err(IndexError::UsizeDowncastError);
+        }
+
+        match NonZeroU16::new(value as u16) {
+            Some(non_zero) => Ok(Index(non_zero)),
+            None => Err(IndexError::ZeroIndexError),
         }
     }
 }
@@ -99,27 +237,104 @@ impl TryFrom<usize> for Index<u32> {
 
     fn try_from(value: usize) -> Result<Self, Self::Error> {
         if value > u32::MAX_VALUE {
-            Err(IndexError::UsizeDowncastError)
-        } else {
-            Ok(Index(value as u32))
+            return Err(IndexError::UsizeDowncastError);
+        }
+
+        match NonZeroU32::new(value as u32) {
+            Some(non_zero) => Ok(Index(non_zero)),
+            None => Err(IndexError::ZeroIndexError),
         }
     }
 }
 
+// The generation starts at zero
+impl TryFrom<usize> for Index<Generational> {
+    type Error = IndexError;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        Index::<Generational>::new(value, 0)
+    }
+}
+
 impl From<Index<u8>> for usize {
     fn from(value: Index<u8>) -> Self {
-        value.0 as usize
+        value.0.get() as usize
     }
 }
 
 impl From<Index<u16>> for usize {
     fn from(value: Index<u16>) -> Self {
-        value.0 as usize
+        value.0.get() as usize
     }
 }
 
 impl From<Index<u32>> for usize {
     fn from(value: Index<u32>) -> Self {
-        value.0 as usize
+        value.0.get() as usize
+    }
+}
+
+// The generation is discarded
+impl From<Index<Generational>> for usize {
+    fn from(value: Index<Generational>) -> Self {
+        value.index()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAST_INDEX: usize = (1 << 20) - 1;
+
+    #[test]
+    fn generational_index_bounds() {
+        assert!(matches!(
+            Index::<Generational>::new(0, 0),
+            Err(IndexError::ZeroIndexError)
+        ));
+        assert!(matches!(
+            Index::<Generational>::new(1 << 20, 0),
+            Err(IndexError::UsizeDowncastError)
+        ));
+
+        let last = Index::<Generational>::new(LAST_INDEX, 0).unwrap();
+        assert_eq!(last.index(), LAST_INDEX);
+        assert_eq!(last.generation(), 0);
+    }
+
+    #[test]
+    fn generation_bounds() {
+        let oldest = Index::<Generational>::new(LAST_INDEX, 4095).unwrap();
+        assert_eq!(oldest.index(), LAST_INDEX);
+        assert_eq!(oldest.generation(), 4095);
+        assert_eq!((oldest.0).0.get(), u32::MAX);
+
+        assert!(matches!(
+            Index::<Generational>::new(1, 4096),
+            Err(IndexError::GenerationOverflowError)
+        ));
+    }
+
+    #[test]
+    fn next_generation_wraps() {
+        let index = Index::<Generational>::new(7, 4094).unwrap();
+
+        let next = index.next_generation();
+        assert_eq!((next.index(), next.generation()), (7, 4095));
+
+        let wrapped = next.next_generation();
+        assert_eq!((wrapped.index(), wrapped.generation()), (7, 0));
+    }
+
+    #[test]
+    fn generational_comparison_ignores_the_generation() {
+        let index = Index::<Generational>::new(LAST_INDEX, 4095).unwrap();
+
+        assert!(index == LAST_INDEX as i32);
+        assert!(index != (LAST_INDEX - 1) as i32);
+        assert!(index != -1);
+        assert!(index != u32::MAX as i32);
+        assert_eq!(usize::from(index), LAST_INDEX);
     }
 }
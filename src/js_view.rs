@@ -0,0 +1,150 @@
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ops::Deref;
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+use crate::web_core::WebCore;
+
+// Element types which have a matching JS typed array.
+pub(crate) trait ViewElement: Copy {
+    type Array: JsCast + AsRef<js_sys::Object>;
+
+    // Creates a typed array over 'length' elements of 'buffer', from 'byte_offset'.
+    fn view_at(buffer: &JsValue, byte_offset: u32, length: u32) -> Self::Array;
+}
+
+macro_rules! view_element {
+    ($t:ty, $array:ty) => {
+        impl ViewElement for $t {
+            type Array = $array;
+
+            fn view_at(buffer: &JsValue, byte_offset: u32, length: u32) -> Self::Array {
+                <$array>::new_with_byte_offset_and_length(buffer, byte_offset, length)
+            }
+        }
+    };
+}
+
+view_element!(u8, js_sys::Uint8Array);
+view_element!(i8, js_sys::Int8Array);
+view_element!(u16, js_sys::Uint16Array);
+view_element!(i16, js_sys::Int16Array);
+view_element!(u32, js_sys::Uint32Array);
+view_element!(i32, js_sys::Int32Array);
+view_element!(f32, js_sys::Float32Array);
+view_element!(f64, js_sys::Float64Array);
+
+// A slice of linear memory, to be handed to JS as a typed array.
+//
+// js_sys::Float32Array::view() (and friends) create a typed array over the current
+// WebAssembly.Memory buffer, which JS detaches as soon as memory grows. Any allocation can grow
+// memory, so holding such a view is a hazard. A JsView only records the byte offset and length,
+// and derives a fresh typed array from the current buffer each time it is used.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct JsView<'a, T: ViewElement> {
+    byte_offset: usize,
+    length: usize,
+    slice: PhantomData<&'a [T]>,
+}
+
+#[allow(dead_code)]
+impl<'a, T: ViewElement> JsView<'a, T> {
+    pub(crate) fn new(slice: &'a [T]) -> Self {
+        JsView {
+            byte_offset: slice.as_ptr() as usize,
+            length: slice.len(),
+            slice: PhantomData,
+        }
+    }
+
+    pub(crate) fn byte_offset(&self) -> usize {
+        self.byte_offset
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.length
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub(crate) fn byte_len(&self) -> usize {
+        self.length * size_of::<T>()
+    }
+
+    // A typed array over the current buffer. Pass it straight to the JS call consuming it: once
+    // memory grows, a typed array kept around reads as empty.
+    pub(crate) fn array(&self) -> T::Array {
+        T::view_at(
+            &current_buffer(),
+            self.byte_offset as u32,
+            self.length as u32,
+        )
+    }
+
+    // A typed array which stays valid while it is alive: the WebCore allocator refuses to grow
+    // memory until the view is dropped (see WasmAllocator::lock_growth()), and allocations which
+    // would need more memory fail instead. With the 'global-allocator' feature this covers every
+    // Vec, String and Box as well.
+    //
+    // Without that feature, the process allocator is a different one, and is not covered.
+    pub(crate) fn raw<'b>(&self, webcore: &'b mut WebCore) -> RawView<'b, T> {
+        webcore.lock_growth();
+
+        RawView {
+            array: self.array(),
+            webcore,
+        }
+    }
+}
+
+// See JsView::raw()
+pub(crate) struct RawView<'b, T: ViewElement> {
+    array: T::Array,
+    webcore: &'b mut WebCore,
+}
+
+impl<'b, T: ViewElement> Drop for RawView<'b, T> {
+    fn drop(&mut self) {
+        self.webcore.unlock_growth();
+    }
+}
+
+impl<'b, T: ViewElement> Deref for RawView<'b, T> {
+    type Target = T::Array;
+
+    fn deref(&self) -> &T::Array {
+        &self.array
+    }
+}
+
+// The ArrayBuffer currently backing the module's WebAssembly.Memory.
+fn current_buffer() -> JsValue {
+    wasm_bindgen::memory()
+        .unchecked_into::<js_sys::WebAssembly::Memory>()
+        .buffer()
+}
+
+// Layout of the dense region of a store, for JS to build typed arrays directly over it (see
+// store_dense_layout() in lib.rs). Offsets are byte offsets into the WebAssembly.Memory buffer.
+//
+// - Element i (0..length) of the data starts at data_offset + i * stride. For a TagVector,
+//   data_offset and stride are 0.
+// - Its key is the unsigned integer of key_stride bytes at keys_offset + i * key_stride. For
+//   generational keys (4 bytes), only the low Generational::INDEX_BITS bits are the key.
+//
+// Offsets stay valid for the lifetime of the store, but length changes with every add or remove,
+// and the buffer is replaced whenever memory grows, so JS should request a fresh layout (and
+// build fresh views) each time it reads.
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug)]
+pub struct DenseLayout {
+    pub data_offset: usize,
+    pub stride: usize,
+    pub length: usize,
+    pub keys_offset: usize,
+    pub key_stride: usize,
+}
@@ -0,0 +1,308 @@
+use std::convert::TryFrom;
+use std::mem::size_of;
+use std::ptr::addr_of_mut;
+
+use super::{console_log, log};
+use crate::error::CoreError;
+use crate::indexing::{Index, IndexType, UnsignedType};
+use crate::membership::MembershipBits;
+
+// The key bookkeeping shared by every store: which keys are present, and at which dense location
+// each of them lives. Stores holding data (KeyVector) mirror every relocation reported here onto
+// their own data array. Stores holding no data (TagVector) simply ignore the relocations.
+//
+// The indices array serves two purposes:
+// - For positions 1..=self.length it holds the key stored at that dense location. A key which
+//   fits inside this region is always stored at its own location (self.indices[key] == key).
+//   Therefore self.indices[1..=self.length] is exactly the dense key list.
+// - For positions past self.length it holds the dense location of that key (None when absent).
+//
+// Reading a position holding None gives 0, which is never a valid key or dense location.
+//
+// When enabled, self.membership mirrors the present keys as a packed bitset.
+pub(crate) struct KeySet<I: UnsignedType, const N: usize> {
+    length: usize,
+    indices: [Option<Index<I>>; N],
+    membership: Option<MembershipBits>,
+}
+
+impl<I: UnsignedType, const N: usize> KeySet<I, N> {
+    // Compile time equivalent of a C++ 'static_assert'. Referencing this constant forces its
+    // evaluation during monomorphization, so a store with an invalid capacity fails the build
+    // instead of panicking on page load.
+    pub(crate) const CAPACITY_CHECK: () = {
+        assert!(N != 0, "Store capacity N must not be zero");
+        assert!(
+            N <= I::MAX_VALUE + 1,
+            "Store capacity N exceeds the range of its index type I"
+        );
+        assert!(
+            size_of::<Option<Index<I>>>() == size_of::<I>(),
+            "Option<Index<I>> must cost no more memory than I"
+        );
+    };
+}
+
+#[allow(dead_code)]
+impl<I: UnsignedType, const N: usize> KeySet<I, N>
+where
+    Index<I>: IndexType,
+{
+    // Until Rust permits 'Placement New' logic, the KeySet is initialized by directly writing
+    // bytes into the backing pool. This sets the length and every index to zero, with the
+    // membership bitset disabled.
+    pub(crate) unsafe fn init(ptr: *mut Self) {
+        let () = Self::CAPACITY_CHECK;
+
+        addr_of_mut!((*ptr).membership).write(None);
+        addr_of_mut!((*ptr).length).write_bytes(0, 1);
+        // WE *MUST* CONFIRM THIS ZEROS THE ENTIRE ARRAY!!!
+        addr_of_mut!((*ptr).indices).write_bytes(0, 1);
+
+        // This confirms that all values within the array [Option<Index<I>>; N] are cleared to None.
+        // The entire array [Option<Index<I>>; N] is cycled
+        // Each value is tested against None.
+        //
+        for i in 0..(*ptr).indices.len() {
+            if (*ptr).indices[i].is_some() {
+                console_log!(
+                    "Invalid zeroing!!! I: {:?}, Value: {:?}",
+                    i,
+                    (*ptr).indices[i]
+                );
+                panic!();
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.length
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub(crate) fn contains(&self, key: usize) -> bool {
+        self.location(key) != 0
+    }
+
+    // Returns the dense location holding the key, or 0 when the key is not present.
+    #[allow(clippy::needless_return)]
+    pub(crate) fn location(&self, key: usize) -> usize {
+        if key == 0 || key >= N {
+            return 0;
+        }
+
+        if key <= self.length {
+            if key == self.index_at(key) {
+                return key;
+            }
+            return 0;
+        }
+
+        return self.index_at(key);
+    }
+
+    // Returns the key stored at a location within the dense region 1..=self.length.
+    pub(crate) fn key_at(&self, location: usize) -> usize {
+        self.index_at(location)
+    }
+
+    // Returns true when the key is within the valid range 1..N and not yet present.
+    #[allow(clippy::needless_return)]
+    pub(crate) fn is_vacant(&self, key: usize) -> bool {
+        if key == 0 || key >= N {
+            return false;
+        }
+
+        return self.location(key) == 0;
+    }
+
+    // Starts maintaining a membership bitset, filled from the keys currently present.
+    pub(crate) fn enable_membership(&mut self) {
+        if self.membership.is_some() {
+            return;
+        }
+
+        let mut membership = MembershipBits::with_capacity(N);
+        for key in self.keys() {
+            membership.insert(key);
+        }
+        self.membership = Some(membership);
+    }
+
+    pub(crate) fn disable_membership(&mut self) {
+        self.membership = None;
+    }
+
+    pub(crate) fn membership(&self) -> Option<&MembershipBits> {
+        self.membership.as_ref()
+    }
+
+    // Address of self.indices[1], the start of the dense key list. Exported to JS, which relies
+    // on Option<Index<I>> having the same representation as I (see CAPACITY_CHECK).
+    pub(crate) fn dense_keys_ptr(&self) -> *const Option<Index<I>> {
+        self.indices.as_ptr().wrapping_add(1)
+    }
+
+    pub(crate) fn keys(&self) -> Keys<'_, I, N> {
+        Keys {
+            set: self,
+            location: 1,
+        }
+    }
+
+    // Reserves a dense location for a key which is not yet present, and updates self.indices for
+    // every key that had to move. Each move is reported through relocate(from, to), in order, so
+    // that the caller can move its data along.
+    #[allow(clippy::needless_return)]
+    pub(crate) fn insert_location<F>(&mut self, key: usize, mut relocate: F) -> usize
+    where
+        F: FnMut(usize, usize),
+    {
+        if let Some(membership) = self.membership.as_mut() {
+            membership.insert(key);
+        }
+
+        let next = self.length + 1;
+
+        // STEP 1: GROW THE DENSE REGION BY ONE
+        // Key 'next' is about to fall inside the dense region, so if it is present it must be
+        // moved onto its own location. Its previous location becomes free.
+        let next_location: usize = self.index_at(next);
+        let free_location = if next_location != 0 {
+            relocate(next_location, next);
+            self.set_index(next, next);
+            next_location
+        } else {
+            next
+        };
+        self.length = next;
+
+        // STEP 2: PLACE THE KEY
+
+        // BRANCH POSSIBILITY #1
+        // CONDITION: The free location is the key's own location
+        if free_location == key {
+            self.set_index(key, key);
+            return key;
+        }
+
+        // BRANCH POSSIBILITY #2
+        // CONDITION: key > self.length
+        // *(Free to place greater key directly at the free location)*
+        if key > self.length {
+            self.set_index(free_location, key);
+            self.set_index(key, free_location);
+            return free_location;
+        }
+
+        // BRANCH POSSIBILITY #3
+        // CONDITION: key <= self.length, and its own location holds a greater key
+        // *(Requires moving the greater key into the free location)*
+        let displaced_key: usize = self.index_at(key);
+
+        relocate(key, free_location);
+        self.set_index(free_location, displaced_key);
+        self.set_index(displaced_key, free_location);
+        self.set_index(key, key);
+
+        return key;
+    }
+
+    // Removes the key stored at a location within the dense region, and fills the gap with the
+    // last entry so the region stays compact. The move is reported through relocate(from, to).
+    //
+    // Returns the removed key.
+    #[allow(clippy::needless_return)]
+    pub(crate) fn remove_location<F>(&mut self, location: usize, mut relocate: F) -> usize
+    where
+        F: FnMut(usize, usize),
+    {
+        let key: usize = self.index_at(location);
+        let last = self.length;
+        let last_key: usize = self.index_at(last);
+
+        if location != last {
+            relocate(last, location);
+        }
+
+        // Position 'last' leaves the dense region. It now only describes where key 'last' is
+        // stored, which is either nowhere, or 'location' (restored below).
+        self.indices[last] = None;
+
+        // A removed key greater than self.length also had a pointer to its location
+        if key != location {
+            self.indices[key] = None;
+        }
+
+        if location != last {
+            self.set_index(location, last_key);
+            self.set_index(last_key, location);
+        }
+
+        if let Some(membership) = self.membership.as_mut() {
+            membership.remove(key);
+        }
+
+        self.length -= 1;
+        return key;
+    }
+
+    // Reads a position of self.indices, where None reads as 0.
+    fn index_at(&self, position: usize) -> usize {
+        match self.indices[position] {
+            Some(index) => index.into(),
+            None => 0,
+        }
+    }
+
+    // Writes a non zero value into a position of self.indices.
+    fn set_index(&mut self, position: usize, value: usize) {
+        self.indices[position] = Some(Self::usize_to_index(value));
+    }
+
+    fn usize_to_index(key: usize) -> Index<I> {
+        match Self::try_usize_to_index(key) {
+            Ok(valid_index) => valid_index,
+            Err(error) => {
+                console_log!("Error: {}", error);
+                panic!();
+            }
+        }
+    }
+
+    pub(crate) fn try_usize_to_index(key: usize) -> Result<Index<I>, CoreError> {
+        Ok(Index::<I>::try_from(key)?)
+    }
+}
+
+// Iterates the present keys in dense order.
+#[allow(dead_code)]
+pub(crate) struct Keys<'a, I: UnsignedType, const N: usize> {
+    set: &'a KeySet<I, N>,
+    location: usize,
+}
+
+impl<'a, I: UnsignedType, const N: usize> Iterator for Keys<'a, I, N>
+where
+    Index<I>: IndexType,
+{
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.location > self.set.length {
+            return None;
+        }
+
+        let key = self.set.key_at(self.location);
+        self.location += 1;
+        Some(key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.set.length + 1 - self.location;
+        (remaining, Some(remaining))
+    }
+}
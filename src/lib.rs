@@ -3,9 +3,21 @@
 // 2) python3 -m http.server
 // 3) http://localhost:8000
 
+pub(crate) mod allocation_trace;
+#[cfg(feature = "allocator-debug")]
+pub(crate) mod allocator_debug;
 pub(crate) mod context;
 
+pub(crate) mod error;
+pub(crate) mod frame_arena;
 pub(crate) mod indexing;
+pub(crate) mod js_view;
+pub(crate) mod key_set;
+pub(crate) mod membership;
+pub(crate) mod memory_backend;
+pub(crate) mod memory_stats;
+pub(crate) mod pool;
+pub(crate) mod stack_arena;
 pub(crate) mod wasm_allocator;
 pub(crate) mod web_core;
 
@@ -16,24 +28,174 @@ extern crate web_sys;
 use wasm_bindgen::prelude::*;
 use web_sys::{WebGl2RenderingContext, WebGlProgram, WebGlShader};
 
+use std::cell::RefCell;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::allocation_trace::TraceReport;
+use crate::error::CoreError;
+use crate::js_view::{DenseLayout, JsView};
+use crate::memory_stats::MemoryStats;
 use crate::web_core::WebCore;
 
+// Routes every Vec, String and Box allocation through WasmAllocator. WebCore reserves its stores
+// from this same allocator (see WebCore::try_new()), so that all linear memory is accounted in
+// one place.
+#[cfg(all(feature = "global-allocator", target_arch = "wasm32"))]
+#[global_allocator]
+static ALLOCATOR: wasm_allocator::WasmAllocator = wasm_allocator::WasmAllocator::new();
+
 #[wasm_bindgen]
 extern "C" {
     pub fn alert(s: &str);
+}
 
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+extern "C" {
     #[wasm_bindgen(js_namespace = console)]
     fn log(a: &str);
 }
 
+// Native builds (cargo test) have no browser console, so console_log!() prints to stdout.
+#[cfg(not(target_arch = "wasm32"))]
+fn log(a: &str) {
+    println!("{}", a);
+}
+
 #[macro_use]
 mod macros {
     #[macro_export]
     macro_rules! console_log {
     ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
 }
+
+    // Expands to the narrowest UnsignedType able to index a store of capacity N.
+    // Example: index_for!(4000) is u16
+    #[macro_export]
+    macro_rules! index_for {
+        ($n:expr) => (<$crate::indexing::IndexWidth<{ $crate::indexing::index_width($n) }> as $crate::indexing::SelectIndex>::Type)
+    }
+
+    // Expands to a KeyVector type declared only by T and N, with the index type inferred.
+    // Example: key_vector!(TestObject, 4000) is KeyVector<TestObject, u16, 4000>
+    #[macro_export]
+    macro_rules! key_vector {
+        ($t:ty, $n:expr) => ($crate::web_core::KeyVector<$t, $crate::index_for!($n), { $n }>)
+    }
+}
+
+thread_local! {
+    // The WebCore created by main(), kept alive for the exported query functions below.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static WEB_CORE: RefCell<Option<WebCore>> = RefCell::new(None);
+
+    // Registered by JS through set_out_of_memory_callback()
+    static OUT_OF_MEMORY_CALLBACK: RefCell<Option<js_sys::Function>> = const { RefCell::new(None) };
+}
+
+// Registers a JS function called with the number of pages whenever the WebCore allocator fails
+// to grow memory, for example to show a "low memory" message. When the function returns a truthy
+// value (after releasing memory through other exports), the failed allocation is retried once.
+// Passing undefined removes the callback.
+#[wasm_bindgen]
+pub fn set_out_of_memory_callback(callback: Option<js_sys::Function>) {
+    OUT_OF_MEMORY_CALLBACK.with(|registered| *registered.borrow_mut() = callback);
+}
+
+// OomHandler of the WebCore allocator, forwarding to the JS callback.
+fn notify_out_of_memory(pages: usize) -> bool {
+    // Cloned out, so the callback itself may register a new callback.
+    let callback = OUT_OF_MEMORY_CALLBACK.with(|registered| registered.borrow().clone());
+
+    match callback {
+        Some(callback) => match callback.call1(&JsValue::NULL, &JsValue::from(pages)) {
+            Ok(result) => result.is_truthy(),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+// Memory report of the WebCore allocator, including the footprint of every store.
+#[wasm_bindgen]
+pub fn memory_stats() -> Result<MemoryStats, JsValue> {
+    WEB_CORE.with(|webcore| match webcore.borrow().as_ref() {
+        Some(webcore) => Ok(webcore.memory_stats()),
+        None => Err(CoreError::Uninitialized.into()),
+    })
+}
+
+// Byte offset, stride and live count of the dense data of a store, plus the matching dense key
+// list. Stores are numbered in creation order, as in memory_stats().
+#[wasm_bindgen]
+pub fn store_dense_layout(store: usize) -> Result<DenseLayout, JsValue> {
+    WEB_CORE.with(|webcore| match webcore.borrow().as_ref() {
+        Some(webcore) => Ok(webcore.try_dense_layout(store)?),
+        None => Err(CoreError::Uninitialized.into()),
+    })
+}
+
+// Ends the current frame of the WebCore allocator: frees the frame arena, and advances the frame
+// number recorded by allocation tracing. Call once per requestAnimationFrame.
+#[wasm_bindgen]
+pub fn end_frame() -> Result<(), JsValue> {
+    WEB_CORE.with(|webcore| match webcore.borrow().as_ref() {
+        Some(webcore) => {
+            webcore.end_frame();
+            Ok(())
+        }
+        None => Err(CoreError::Uninitialized.into()),
+    })
+}
+
+// Starts recording the allocations of the WebCore allocator, with room for 'records' live
+// allocations. See allocation_report().
+#[wasm_bindgen]
+pub fn enable_allocation_tracing(records: usize) -> Result<(), JsValue> {
+    WEB_CORE.with(|webcore| match webcore.borrow().as_ref() {
+        Some(webcore) => Ok(webcore.try_enable_tracing(records)?),
+        None => Err(CoreError::Uninitialized.into()),
+    })
+}
+
+#[wasm_bindgen]
+pub fn disable_allocation_tracing() -> Result<(), JsValue> {
+    WEB_CORE.with(|webcore| match webcore.borrow().as_ref() {
+        Some(webcore) => {
+            webcore.disable_tracing();
+            Ok(())
+        }
+        None => Err(CoreError::Uninitialized.into()),
+    })
+}
+
+// Live allocations recorded since tracing was enabled, grouped by tag (the store type name for
+// stores). Comparing two reports taken around a level load shows what leaked.
+#[wasm_bindgen]
+pub fn allocation_report() -> Result<TraceReport, JsValue> {
+    WEB_CORE.with(|webcore| match webcore.borrow().as_ref() {
+        Some(webcore) => Ok(webcore.trace_report()),
+        None => Err(CoreError::Uninitialized.into()),
+    })
+}
+
+// Checks the guard bytes around every block of the WebCore allocator, see the 'allocator-debug'
+// feature. Fails with the origin of the first corrupted block.
+#[cfg(feature = "allocator-debug")]
+#[wasm_bindgen]
+pub fn check_guards() -> Result<(), JsValue> {
+    WEB_CORE.with(|webcore| match webcore.borrow().as_ref() {
+        Some(webcore) => Ok(webcore.try_check_guards()?),
+        None => Err(CoreError::Uninitialized.into()),
+    })
+}
+
+// Memory report of the process allocator, which WebCore shares. Unlike memory_stats(), the
+// footprint of each store is not listed.
+#[cfg(all(feature = "global-allocator", target_arch = "wasm32"))]
+#[wasm_bindgen]
+pub fn global_memory_stats() -> MemoryStats {
+    ALLOCATOR.memory_stats()
 }
 
 /*
@@ -114,6 +276,7 @@ pub fn link_program(
     }
 }
 
+#[allow(dead_code)]
 fn perf_to_system(amt: f64) -> SystemTime {
     let secs = (amt as u64) / 1_000;
     let nanos = (((amt as u64) % 1_000) as u32) * 1_000_000;
@@ -127,6 +290,7 @@ fn perf_to_system(amt: f64) -> SystemTime {
 fn main() -> Result<(), JsValue> {
     let window = web_sys::window().expect("no global `window` exists");
     let document = window.document().expect("should have a document on window");
+    #[allow(unused_variables)]
     let body = document.body().expect("document should have a body");
 
     let canvas = document.get_element_by_id("canvas").unwrap();
@@ -139,7 +303,8 @@ fn main() -> Result<(), JsValue> {
     console_log!("the current time (in ms) is {}", performance.now());
 
     let mut webcore: WebCore = WebCore::new();
-    let test_keyvec = webcore.addkeyvec::<TestObject, u16, 4000>();
+    webcore.set_oom_handler(Some(notify_out_of_memory));
+    let test_keyvec: *mut key_vector!(TestObject, 4000) = webcore.try_addkeyvec()?;
 
     unsafe {
         (*test_keyvec).add(1);
@@ -198,16 +363,11 @@ fn main() -> Result<(), JsValue> {
     let buffer = context.create_buffer().ok_or("Failed to create buffer")?;
     context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&buffer));
 
-    // Note that `Float32Array::view` is somewhat dangerous (hence the
-    // `unsafe`!). This is creating a raw view into our module's
-    // `WebAssembly.Memory` buffer, but if we allocate more pages for ourself
-    // (aka do a memory allocation in Rust) it'll cause the buffer to change,
-    // causing the `Float32Array` to be invalid.
-    //
-    // As a result, after `Float32Array::view` we have to be very careful not to
-    // do any memory allocations before it's dropped.
-    unsafe {
-        let positions_array_buf_view = js_sys::Float32Array::view(&vertices);
+    // Memory growth (which would detach the view's buffer) is refused by the WebCore allocator
+    // until the raw view is dropped.
+    let positions = JsView::new(&vertices);
+    {
+        let positions_array_buf_view = positions.raw(&mut webcore);
 
         context.buffer_data_with_array_buffer_view(
             WebGl2RenderingContext::ARRAY_BUFFER,
@@ -266,6 +426,8 @@ fn main() -> Result<(), JsValue> {
     //body.append_child(&val)?;
 
     console_log!("the current time (in ms) is {}", performance.now());
+
+    WEB_CORE.with(|core| *core.borrow_mut() = Some(webcore));
     Ok(())
 }
 
@@ -0,0 +1,118 @@
+// A packed membership bitset, holding one bit per key. Stores can optionally maintain one next to
+// their indices, so that queries across several stores ("has all of") run a word at a time
+// instead of probing each store's indices per key.
+
+const WORD_BITS: usize = 64;
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MembershipBits {
+    words: Vec<u64>,
+}
+
+#[allow(dead_code)]
+impl MembershipBits {
+    // Creates an empty bitset able to hold the keys 0..capacity.
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        MembershipBits {
+            words: vec![0; capacity.div_ceil(WORD_BITS)],
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: usize) {
+        self.words[key / WORD_BITS] |= 1 << (key % WORD_BITS);
+    }
+
+    pub(crate) fn remove(&mut self, key: usize) {
+        self.words[key / WORD_BITS] &= !(1 << (key % WORD_BITS));
+    }
+
+    pub(crate) fn contains(&self, key: usize) -> bool {
+        match self.words.get(key / WORD_BITS) {
+            Some(word) => (word >> (key % WORD_BITS)) & 1 == 1,
+            None => false,
+        }
+    }
+
+    pub(crate) fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    // Returns the number of keys set.
+    pub(crate) fn count(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    // Keeps only the keys which are also set in other.
+    pub(crate) fn intersect_with(&mut self, other: &MembershipBits) {
+        let shared = self.words.len().min(other.words.len());
+
+        for (word, other_word) in self.words[..shared].iter_mut().zip(&other.words) {
+            *word &= *other_word;
+        }
+
+        // Keys past the end of 'other' cannot be members of it.
+        for word in self.words[shared..].iter_mut() {
+            *word = 0;
+        }
+    }
+
+    // Iterates the keys set, in ascending order.
+    pub(crate) fn iter(&self) -> Ones<'_> {
+        Ones {
+            words: &self.words,
+            word_index: 0,
+            current: self.words.first().copied().unwrap_or(0),
+        }
+    }
+}
+
+// Returns the keys present in every one of the sets, computed a word at a time.
+#[allow(dead_code)]
+pub(crate) fn intersect(sets: &[&MembershipBits]) -> MembershipBits {
+    let mut result = match sets.first() {
+        Some(first) => (*first).clone(),
+        None => return MembershipBits::default(),
+    };
+
+    for set in &sets[1..] {
+        result.intersect_with(set);
+    }
+
+    result
+}
+
+// Returns true when the key is present in every one of the sets.
+#[allow(dead_code)]
+pub(crate) fn has_all(sets: &[&MembershipBits], key: usize) -> bool {
+    sets.iter().all(|set| set.contains(key))
+}
+
+#[allow(dead_code)]
+pub(crate) struct Ones<'a> {
+    words: &'a [u64],
+    word_index: usize,
+    current: u64,
+}
+
+impl<'a> Iterator for Ones<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.current == 0 {
+            self.word_index += 1;
+            if self.word_index >= self.words.len() {
+                return None;
+            }
+            self.current = self.words[self.word_index];
+        }
+
+        let bit = self.current.trailing_zeros() as usize;
+        // Clear the lowest set bit
+        self.current &= self.current - 1;
+
+        Some(self.word_index * WORD_BITS + bit)
+    }
+}
@@ -0,0 +1,124 @@
+use core::cell::Cell;
+use core::ptr::null_mut;
+use std::alloc::{GlobalAlloc, Layout, System};
+
+pub(crate) const PAGE_SIZE: usize = 65536;
+
+// The linear memory a WasmAllocator grows into. On wasm32 this is the module's own memory. On
+// other targets it is simulated, so that the allocator (and everything built on top of it) can
+// run under 'cargo test'.
+pub(crate) trait MemoryBackend {
+    // Current size, in pages.
+    #[allow(dead_code)]
+    fn memory_size(&self) -> usize;
+
+    // Grows by 'pages', returning the previous size in pages, or usize::MAX when out of memory.
+    // Same contract as core::arch::wasm32::memory_grow().
+    fn memory_grow(&self, pages: usize) -> usize;
+
+    // Address of the first byte of a page.
+    fn page_ptr(&self, page: usize) -> *mut u8;
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) type DefaultMemory = Wasm32Memory;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type DefaultMemory = SimulatedMemory;
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) struct Wasm32Memory;
+
+#[cfg(target_arch = "wasm32")]
+impl Wasm32Memory {
+    pub(crate) const fn new() -> Self {
+        Wasm32Memory
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl MemoryBackend for Wasm32Memory {
+    fn memory_size(&self) -> usize {
+        core::arch::wasm32::memory_size(0)
+    }
+
+    fn memory_grow(&self, pages: usize) -> usize {
+        core::arch::wasm32::memory_grow(0, pages)
+    }
+
+    fn page_ptr(&self, page: usize) -> *mut u8 {
+        (page * PAGE_SIZE) as *mut u8
+    }
+}
+
+// Simulates page growth over a host buffer, reserved on the first growth. Growing past
+// max_pages fails exactly like memory_grow() does on wasm32, which lets out of memory conditions
+// be tested with a small limit.
+//
+// The buffer comes from the System allocator directly, so this backend stays usable even when a
+// WasmAllocator is the #[global_allocator].
+pub(crate) struct SimulatedMemory {
+    base: Cell<*mut u8>,
+    pages: Cell<usize>,
+    max_pages: usize,
+}
+
+impl SimulatedMemory {
+    pub(crate) const DEFAULT_MAX_PAGES: usize = 4096;
+
+    pub(crate) const fn new() -> Self {
+        SimulatedMemory::with_limit(SimulatedMemory::DEFAULT_MAX_PAGES)
+    }
+
+    pub(crate) const fn with_limit(max_pages: usize) -> Self {
+        SimulatedMemory {
+            base: Cell::new(null_mut()),
+            pages: Cell::new(0),
+            max_pages,
+        }
+    }
+
+    fn buffer_layout(&self) -> Layout {
+        Layout::from_size_align(self.max_pages * PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+
+    fn reserve(&self) -> *mut u8 {
+        if self.base.get().is_null() {
+            self.base.set(unsafe { System.alloc(self.buffer_layout()) });
+        }
+        self.base.get()
+    }
+}
+
+impl MemoryBackend for SimulatedMemory {
+    fn memory_size(&self) -> usize {
+        self.pages.get()
+    }
+
+    fn memory_grow(&self, pages: usize) -> usize {
+        let previous = self.pages.get();
+
+        if pages > self.max_pages - previous {
+            return usize::MAX;
+        }
+
+        if pages != 0 && self.reserve().is_null() {
+            return usize::MAX;
+        }
+
+        self.pages.set(previous + pages);
+        previous
+    }
+
+    fn page_ptr(&self, page: usize) -> *mut u8 {
+        self.base.get().wrapping_add(page * PAGE_SIZE)
+    }
+}
+
+impl Drop for SimulatedMemory {
+    fn drop(&mut self) {
+        if !self.base.get().is_null() {
+            unsafe { System.dealloc(self.base.get(), self.buffer_layout()) };
+        }
+    }
+}
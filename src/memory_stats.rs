@@ -0,0 +1,44 @@
+use wasm_bindgen::prelude::*;
+
+use crate::js_view::DenseLayout;
+
+// Memory footprint of a single store created through WebCore, and where to find it.
+#[derive(Clone, Debug)]
+pub(crate) struct StoreFootprint {
+    pub(crate) type_name: &'static str,
+    pub(crate) bytes: usize,
+    pub(crate) location: *const u8,
+    // Type erased KeyVector::dense_layout() (or TagVector::dense_layout()), called on location.
+    pub(crate) dense_layout: fn(*const u8) -> DenseLayout,
+}
+
+// Structured report of the linear memory managed by a WasmAllocator, and of the stores
+// constructed inside of it. Exported to JavaScript through memory_stats() in lib.rs.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStats {
+    pub pages_grown: usize,
+    pub bytes_reserved: usize,
+    pub bytes_in_use: usize,
+    pub largest_free_block: usize,
+    pub allocation_count: usize,
+    pub internal_fragmentation: usize,
+    pub(crate) stores: Vec<StoreFootprint>,
+}
+
+#[wasm_bindgen]
+impl MemoryStats {
+    pub fn store_count(&self) -> usize {
+        self.stores.len()
+    }
+
+    pub fn store_type_name(&self, store: usize) -> Option<String> {
+        self.stores
+            .get(store)
+            .map(|footprint| footprint.type_name.to_string())
+    }
+
+    pub fn store_bytes(&self, store: usize) -> Option<usize> {
+        self.stores.get(store).map(|footprint| footprint.bytes)
+    }
+}
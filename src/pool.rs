@@ -0,0 +1,147 @@
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr::null_mut;
+use std::alloc::Layout;
+use std::any::type_name;
+
+use super::{console_log, log};
+use crate::error::CoreError;
+use crate::memory_backend::{DefaultMemory, MemoryBackend, PAGE_SIZE};
+use crate::wasm_allocator::WasmAllocator;
+
+// Link written into the first bytes of every free slot.
+#[allow(dead_code)]
+struct PoolSlot {
+    next: *mut PoolSlot,
+}
+
+// Fixed size object pool. Slots are carved out of whole pages taken from the allocator's bump
+// arena, one page at a time as the pool runs out, and recycled through an intrusive free list.
+// Both alloc and free are O(1).
+//
+// Pages stay with the pool for the lifetime of the allocator, like the stores of WebCore.
+// Values still live when the pool is dropped are not dropped.
+#[allow(dead_code)]
+pub(crate) struct Pool<'a, T, B: MemoryBackend = DefaultMemory> {
+    wasm_allocator: &'a WasmAllocator<B>,
+    free_list: *mut PoolSlot,
+    live: usize,
+    capacity: usize,
+    pages: usize,
+    marker: PhantomData<T>,
+}
+
+#[allow(dead_code)]
+impl<'a, T, B: MemoryBackend> Pool<'a, T, B> {
+    // A slot holds either a T, or the free list link.
+    const SLOT_SIZE: usize = {
+        let size = if size_of::<T>() > size_of::<PoolSlot>() {
+            size_of::<T>()
+        } else {
+            size_of::<PoolSlot>()
+        };
+        size.next_multiple_of(Self::SLOT_ALIGN)
+    };
+
+    const SLOT_ALIGN: usize = if align_of::<T>() > align_of::<PoolSlot>() {
+        align_of::<T>()
+    } else {
+        align_of::<PoolSlot>()
+    };
+
+    // Objects larger than a page grow the pool by as many pages as a single slot needs.
+    const GROWTH_PAGES: usize = Self::SLOT_SIZE.div_ceil(PAGE_SIZE);
+
+    pub(crate) fn new(wasm_allocator: &'a WasmAllocator<B>) -> Self {
+        Pool {
+            wasm_allocator,
+            free_list: null_mut(),
+            live: 0,
+            capacity: 0,
+            pages: 0,
+            marker: PhantomData,
+        }
+    }
+
+    pub(crate) fn alloc(&mut self, value: T) -> *mut T {
+        match self.try_alloc(value) {
+            Ok(ptr) => ptr,
+            Err(error) => {
+                console_log!("[Pool::alloc()] ERROR: {}", error);
+                panic!();
+            }
+        }
+    }
+
+    pub(crate) fn try_alloc(&mut self, value: T) -> Result<*mut T, CoreError> {
+        if self.free_list.is_null() {
+            self.try_grow()?;
+        }
+
+        let slot = self.free_list;
+        unsafe {
+            self.free_list = (*slot).next;
+
+            let ptr = slot as *mut T;
+            ptr.write(value);
+
+            self.live += 1;
+            Ok(ptr)
+        }
+    }
+
+    // Drops the value, and returns its slot to the free list.
+    //
+    // 'ptr' must have been returned by alloc() of this pool, and not freed since.
+    pub(crate) unsafe fn free(&mut self, ptr: *mut T) {
+        ptr.drop_in_place();
+
+        let slot = ptr as *mut PoolSlot;
+        slot.write(PoolSlot {
+            next: self.free_list,
+        });
+        self.free_list = slot;
+
+        self.live -= 1;
+    }
+
+    // Number of values allocated and not yet freed.
+    pub(crate) fn live(&self) -> usize {
+        self.live
+    }
+
+    // Number of slots carved so far, live or free.
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn pages(&self) -> usize {
+        self.pages
+    }
+
+    // Takes the next page (or pages, for objects larger than a page) and threads its slots onto
+    // the free list, lowest address first.
+    fn try_grow(&mut self) -> Result<(), CoreError> {
+        let bytes = Self::GROWTH_PAGES * PAGE_SIZE;
+        let layout = unsafe { Layout::from_size_align_unchecked(bytes, Self::SLOT_ALIGN) };
+        let page = unsafe {
+            self.wasm_allocator
+                .try_alloc_tagged(layout, type_name::<T>())?
+        };
+
+        let slots = bytes / Self::SLOT_SIZE;
+        for slot_index in (0..slots).rev() {
+            unsafe {
+                let slot = page.add(slot_index * Self::SLOT_SIZE) as *mut PoolSlot;
+                slot.write(PoolSlot {
+                    next: self.free_list,
+                });
+                self.free_list = slot;
+            }
+        }
+
+        self.capacity += slots;
+        self.pages += Self::GROWTH_PAGES;
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use core::cell::Cell;
+use core::mem::forget;
+use core::ptr::null_mut;
+use std::alloc::Layout;
+
+use super::{console_log, log};
+use crate::error::CoreError;
+
+// A region of memory allocated in LIFO order. marker() captures the current top, and
+// rollback(marker) frees everything allocated after it. Markers nest, and must be rolled back
+// innermost first.
+#[allow(dead_code)]
+pub(crate) struct StackArena {
+    start: Cell<*mut u8>,
+    top: Cell<*mut u8>,
+    end: Cell<*mut u8>,
+    // Number of markers taken and not yet rolled back.
+    depth: Cell<usize>,
+}
+
+// A captured stack top. Deliberately neither Copy nor Clone: a successful rollback consumes the
+// marker, so the same marker cannot be rolled back twice.
+//
+// A marker which is dropped instead leaves its depth taken, and every outer marker is rejected
+// until the region is reset. Debug builds log it.
+#[derive(Debug)]
+#[must_use = "a marker must be rolled back, or every outer marker is rejected"]
+#[allow(dead_code)]
+pub(crate) struct StackMarker {
+    top: *mut u8,
+    depth: usize,
+}
+
+impl Drop for StackMarker {
+    fn drop(&mut self) {
+        if cfg!(debug_assertions) {
+            console_log!(
+                "[StackMarker] ERROR: Marker at depth {} dropped without a rollback",
+                self.depth
+            );
+        }
+    }
+}
+
+// A rollback rejected by try_rollback(). The marker is handed back, so that it can still be
+// rolled back once the markers taken after it are.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub(crate) struct RejectedRollback {
+    pub(crate) marker: StackMarker,
+    pub(crate) error: CoreError,
+}
+
+impl From<RejectedRollback> for CoreError {
+    fn from(rejected: RejectedRollback) -> Self {
+        rejected.error
+    }
+}
+
+#[allow(dead_code)]
+impl StackArena {
+    pub(crate) const fn new() -> Self {
+        StackArena {
+            start: Cell::new(null_mut()),
+            top: Cell::new(null_mut()),
+            end: Cell::new(null_mut()),
+            depth: Cell::new(0),
+        }
+    }
+
+    // Hands a new region to the stack. Every allocation and marker of the previous region is
+    // released.
+    pub(crate) fn set_region(&self, start: *mut u8, size: usize) {
+        self.start.set(start);
+        self.top.set(start);
+        self.end.set(start.wrapping_add(size));
+        self.depth.set(0);
+    }
+
+    pub(crate) fn region(&self) -> (*mut u8, usize) {
+        (self.start.get(), self.capacity())
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.end.get() as usize - self.start.get() as usize
+    }
+
+    pub(crate) fn used(&self) -> usize {
+        self.top.get() as usize - self.start.get() as usize
+    }
+
+    pub(crate) fn try_alloc_layout(&self, layout: Layout) -> Result<*mut u8, CoreError> {
+        let top_usize = self.top.get() as usize;
+        let padding = top_usize.wrapping_neg() & (layout.align() - 1);
+        let available = self.end.get() as usize - top_usize;
+
+        if padding + layout.size() > available {
+            return Err(CoreError::ArenaExhausted {
+                requested: layout.size(),
+                available,
+            });
+        }
+
+        let ptr = (top_usize + padding) as *mut u8;
+        self.top.set(ptr.wrapping_add(layout.size()));
+        Ok(ptr)
+    }
+
+    pub(crate) fn marker(&self) -> StackMarker {
+        let depth = self.depth.get() + 1;
+        self.depth.set(depth);
+
+        StackMarker {
+            top: self.top.get(),
+            depth,
+        }
+    }
+
+    // Frees everything allocated after the marker was taken. Rejected when the marker is not the
+    // innermost one still outstanding, or belongs to a previous region. The stack is left
+    // untouched in that case, and the marker is returned with the error.
+    pub(crate) fn try_rollback(&self, marker: StackMarker) -> Result<(), RejectedRollback> {
+        let in_region = self.start.get() <= marker.top && marker.top <= self.top.get();
+
+        if marker.depth != self.depth.get() || !in_region {
+            let error = CoreError::OutOfOrderRollback {
+                depth: marker.depth,
+                innermost: self.depth.get(),
+            };
+            return Err(RejectedRollback { marker, error });
+        }
+
+        self.top.set(marker.top);
+        self.depth.set(marker.depth - 1);
+        forget(marker);
+        Ok(())
+    }
+
+    // Frees every allocation and forgets every marker.
+    pub(crate) fn reset(&self) {
+        self.top.set(self.start.get());
+        self.depth.set(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejected_rollback_returns_the_marker() {
+        let mut buffer = [0u8; 256];
+        let stack = StackArena::new();
+        stack.set_region(buffer.as_mut_ptr(), buffer.len());
+
+        let outer = stack.marker();
+        stack.try_alloc_layout(Layout::new::<u64>()).unwrap();
+        let inner = stack.marker();
+        stack.try_alloc_layout(Layout::new::<u64>()).unwrap();
+
+        let outer = match stack.try_rollback(outer) {
+            Err(rejected) => rejected.marker,
+            Ok(()) => panic!("outer marker rolled back before the inner one"),
+        };
+        assert_eq!(stack.used(), 16);
+
+        stack.try_rollback(inner).unwrap();
+        stack.try_rollback(outer).unwrap();
+        assert_eq!(stack.used(), 0);
+    }
+}
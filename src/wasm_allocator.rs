@@ -1,64 +1,1315 @@
-use core::ptr::null_mut;
+use core::cell::Cell;
+use core::mem::{align_of, size_of};
+use core::ptr::{copy_nonoverlapping, null_mut};
 use std::alloc::{GlobalAlloc, Layout};
 
 use super::{console_log, log};
+use crate::allocation_trace::{TraceRecord, TraceReport, TraceTable};
+#[cfg(feature = "allocator-debug")]
+use crate::allocator_debug::GuardList;
+use crate::error::CoreError;
+use crate::frame_arena::{FrameArena, FramePtr};
+use crate::memory_backend::{DefaultMemory, MemoryBackend, PAGE_SIZE};
+use crate::memory_stats::MemoryStats;
+use crate::pool::Pool;
+use crate::stack_arena::{RejectedRollback, StackArena, StackMarker};
 
-const PAGE_SIZE: usize = 65536;
+// Small requests are rounded up to a power of two size class, from 16 bytes (large enough to
+// hold a FreeBlock header) up to half a page (32 KiB). Only larger requests take the large block
+// path, which hands out whole pages.
+const MIN_CLASS_SHIFT: u32 = 4;
+const MAX_CLASS_SHIFT: u32 = 15;
+const SIZE_CLASSES: usize = (MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1) as usize;
+const MAX_SMALL_SIZE: usize = 1 << MAX_CLASS_SHIFT;
+
+// An empty size class is refilled by carving a span of this many bytes (or a single block, for
+// larger classes) into blocks. Spans are aligned to their size, so that the blocks of a span can
+// be found from their addresses alone.
+const SPAN_SIZE: usize = 4096;
+
+// Header written into the first bytes of every freed block, linking it into a free list.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+// Called when growing memory fails, with the number of pages that could not be grown. Returns
+// true to have the failed allocation retried once, typically after freeing caches. When the
+// allocator is the #[global_allocator] the handler must not allocate.
+pub(crate) type OomHandler = fn(pages: usize) -> bool;
 
 // Consider changing visibilities to pub(crate)
-pub(crate) struct WasmAllocator {
-    pub lead_ptr: *mut u8,
-    pub tracking_ptr: *mut u8,
-    pub allocation_size: usize,
+pub(crate) struct WasmAllocator<B: MemoryBackend = DefaultMemory> {
+    backend: B,
+    pub lead_ptr: Cell<*mut u8>,
+    pub tracking_ptr: Cell<*mut u8>,
+    // Bytes grown but not yet handed out, from tracking_ptr to the end of the grown region.
+    pub allocation_size: Cell<usize>,
+    // One free list per small size class, each holding blocks of exactly that size.
+    small_free_lists: [Cell<*mut FreeBlock>; SIZE_CLASSES],
+    // Freed large blocks (and leftover regions), of any size. Sorted by address, so that
+    // neighbouring blocks are merged as they are freed. Searched first fit.
+    large_free_list: Cell<*mut FreeBlock>,
+    // Live GlobalAlloc bytes, as requested by the callers and as granted by the size classes.
+    requested_bytes: Cell<usize>,
+    granted_bytes: Cell<usize>,
+    // Bytes handed out directly through alloc_layout(), which are never freed.
+    arena_bytes: Cell<usize>,
+    // Live allocations, through both GlobalAlloc and alloc_layout().
+    allocation_count: Cell<usize>,
+    pages_grown: Cell<usize>,
+    // Scratch region for allocations living exactly one frame, see reserve_frame_arena().
+    frame_arena: FrameArena,
+    // Scratch region for nested temporary work, see reserve_stack_arena().
+    #[allow(dead_code)]
+    stack_arena: StackArena,
+    oom_handler: Cell<Option<OomHandler>>,
+    // Set while the handler runs, so an allocation failing inside it is not handled again.
+    in_oom_handler: Cell<bool>,
+    // Live allocations with their tag and frame, see enable_tracing().
+    trace_table: TraceTable,
+    // Raw views alive, see lock_growth(). Memory does not grow while there are any.
+    growth_locks: Cell<usize>,
+    // Tag recorded for GlobalAlloc allocations, which have no caller information of their own.
+    // Also reported as their origin by the guard checks.
+    trace_tag: Cell<&'static str>,
+    // Every live block, with its canaries, see the 'allocator-debug' feature.
+    #[cfg(feature = "allocator-debug")]
+    guards: GuardList,
 }
 
+// The wasm32 target is single threaded, so the Cells above are never accessed concurrently. This
+// is required for the allocator to be installed as the #[global_allocator]. Native targets are
+// not single threaded, so there the allocator stays !Sync.
+#[cfg(target_arch = "wasm32")]
+unsafe impl<B: MemoryBackend> Sync for WasmAllocator<B> {}
+
 impl WasmAllocator {
-    pub(crate) fn memory_size() -> usize {
-        core::arch::wasm32::memory_size(0)
+    pub(crate) const fn new() -> Self {
+        WasmAllocator::with_backend(DefaultMemory::new())
+    }
+}
+
+#[allow(dead_code)]
+impl<B: MemoryBackend> WasmAllocator<B> {
+    // The starting pointer value where we can begin constructing objects is only known once
+    // memory is grown for the first time. This ignores the initial allocated pages created
+    // before our process starts...
+    pub(crate) const fn with_backend(backend: B) -> Self {
+        WasmAllocator {
+            backend,
+            lead_ptr: Cell::new(null_mut()),
+            tracking_ptr: Cell::new(null_mut()),
+            allocation_size: Cell::new(0),
+            small_free_lists: [const { Cell::new(null_mut()) }; SIZE_CLASSES],
+            large_free_list: Cell::new(null_mut()),
+            requested_bytes: Cell::new(0),
+            granted_bytes: Cell::new(0),
+            arena_bytes: Cell::new(0),
+            allocation_count: Cell::new(0),
+            pages_grown: Cell::new(0),
+            frame_arena: FrameArena::new(),
+            stack_arena: StackArena::new(),
+            oom_handler: Cell::new(None),
+            in_oom_handler: Cell::new(false),
+            trace_table: TraceTable::new(),
+            growth_locks: Cell::new(0),
+            trace_tag: Cell::new("GlobalAlloc"),
+            #[cfg(feature = "allocator-debug")]
+            guards: GuardList::new(),
+        }
+    }
+
+    // Current size of the linear memory, in pages. This includes pages grown by others.
+    pub(crate) fn memory_size(&self) -> usize {
+        self.backend.memory_size()
+    }
+
+    pub(crate) unsafe fn internal_alloc(&self, pages: usize) -> *mut u8 {
+        match self.try_internal_alloc(pages) {
+            Ok(ptr) => ptr,
+            Err(error) => {
+                // When hooked up to the GlobalAlloc::alloc() function, returning null_mut() is the
+                // fail condition. However, we must manually panic!() here if we are bypassing
+                // GlobalAlloc altogether.
+                console_log!("[WasmAllocator::internal_alloc()] ERROR: {}", error);
+                panic!();
+            }
+        }
     }
 
-    pub(crate) unsafe fn internal_alloc(&mut self, pages: usize) -> *mut u8 {
-        let ptr = core::arch::wasm32::memory_grow(0, pages);
+    pub(crate) unsafe fn try_internal_alloc(&self, pages: usize) -> Result<*mut u8, CoreError> {
+        if self.growth_locks.get() != 0 {
+            return Err(CoreError::GrowthLocked {
+                views: self.growth_locks.get(),
+            });
+        }
+
+        let page = self.backend.memory_grow(pages);
 
-        if ptr != usize::MAX {
-            self.allocation_size += pages * PAGE_SIZE;
-            let ptr = (ptr * PAGE_SIZE) as *mut u8;
-            ptr
+        if page == usize::MAX {
+            return Err(CoreError::OutOfMemory { pages });
+        }
+
+        self.pages_grown.set(self.pages_grown.get() + pages);
+
+        let ptr = self.backend.page_ptr(page);
+        let grown_size = pages * PAGE_SIZE;
+        let region_end = self.tracking_ptr.get() as usize + self.allocation_size.get();
+
+        if self.lead_ptr.get().is_null() {
+            self.lead_ptr.set(ptr);
+            self.tracking_ptr.set(ptr);
+            self.allocation_size.set(grown_size);
+        } else if region_end == ptr as usize {
+            self.allocation_size
+                .set(self.allocation_size.get() + grown_size);
         } else {
-            // When hooked up to the GlobalAlloc::alloc() function, returning null_mut() is the
-            // fail condition. However, we must manually panic!() here if we are bypassing
-            // GlobalAlloc altogether.
+            // Another allocator grew the memory in between, so the new pages do not extend our
+            // region. Recycle what remains of the old region, and continue from the new pages.
+            self.push_large_block(self.tracking_ptr.get(), self.allocation_size.get());
+            self.tracking_ptr.set(ptr);
+            self.allocation_size.set(grown_size);
+        }
 
-            //null_mut() // Not correct when skipping GlobalAlloc
-            console_log!("[WasmAllocator::internal_alloc()] ERROR: Out of memory condition");
-            panic!();
+        Ok(ptr)
+    }
+
+    // Forbids growing memory until the matching unlock_growth(), while a raw view of the memory
+    // buffer is alive (growing memory detaches it, see JsView::raw()). Allocations which would
+    // need more memory fail instead, and GlobalAlloc returns null for them.
+    pub(crate) fn lock_growth(&self) {
+        self.growth_locks.set(self.growth_locks.get() + 1);
+    }
+
+    pub(crate) fn unlock_growth(&self) {
+        self.growth_locks.set(self.growth_locks.get() - 1);
+    }
+
+    // Registers the handler called when growing memory fails, see OomHandler. None removes it.
+    pub(crate) fn set_oom_handler(&self, oom_handler: Option<OomHandler>) {
+        self.oom_handler.set(oom_handler);
+    }
+
+    // Runs an allocation attempt, and when it fails for lack of memory, gives the OOM handler a
+    // chance to free memory before trying exactly once more.
+    fn retry_on_oom<F>(&self, mut attempt: F) -> Result<*mut u8, CoreError>
+    where
+        F: FnMut() -> Result<*mut u8, CoreError>,
+    {
+        let pages = match attempt() {
+            Err(CoreError::OutOfMemory { pages }) => pages,
+            result => return result,
+        };
+
+        let oom_handler = match self.oom_handler.get() {
+            Some(oom_handler) if !self.in_oom_handler.get() => oom_handler,
+            _ => return Err(CoreError::OutOfMemory { pages }),
+        };
+
+        self.in_oom_handler.set(true);
+        let retry = oom_handler(pages);
+        self.in_oom_handler.set(false);
+
+        if !retry {
+            return Err(CoreError::OutOfMemory { pages });
         }
+
+        attempt()
     }
 
     pub(crate) fn debug_allocation_size(&self) {
-        console_log!("allocation_size {} bytes", self.allocation_size);
+        console_log!("allocation_size {} bytes", self.allocation_size.get());
+    }
+
+    // Bytes lost to rounding live allocations up to their size class (or to whole pages).
+    pub(crate) fn internal_fragmentation(&self) -> usize {
+        self.granted_bytes.get() - self.requested_bytes.get()
+    }
+
+    // Snapshot of the allocator's counters. The per store footprints are filled in by WebCore.
+    pub(crate) fn memory_stats(&self) -> MemoryStats {
+        let bytes_reserved = self.pages_grown.get() * PAGE_SIZE;
+
+        MemoryStats {
+            pages_grown: self.pages_grown.get(),
+            bytes_reserved,
+            bytes_in_use: self.arena_bytes.get() + self.granted_bytes.get(),
+            largest_free_block: self.largest_free_block(),
+            allocation_count: self.allocation_count.get(),
+            internal_fragmentation: self.internal_fragmentation(),
+            ..Default::default()
+        }
+    }
+
+    // The largest request which can be served without growing memory.
+    pub(crate) fn largest_free_block(&self) -> usize {
+        let mut largest = self.allocation_size.get();
+
+        unsafe {
+            let mut block = self.large_free_list.get();
+            while !block.is_null() {
+                largest = largest.max((*block).size);
+                block = (*block).next;
+            }
+        }
+
+        for class in (0..SIZE_CLASSES).rev() {
+            if !self.small_free_lists[class].get().is_null() {
+                largest = largest.max(Self::class_size(class));
+                break;
+            }
+        }
+
+        largest
+    }
+
+    pub(crate) fn debug_fragmentation(&self) {
+        console_log!(
+            "requested {} bytes, granted {} bytes, internal fragmentation {} bytes",
+            self.requested_bytes.get(),
+            self.granted_bytes.get(),
+            self.internal_fragmentation()
+        );
+    }
+
+    // Bump arena allocation: hands out the next bytes of the grown region, padded up to the
+    // required alignment. tracking_ptr advances past the block, and the remaining capacity
+    // (allocation_size) shrinks by the block plus its padding. Memory is grown by whole pages
+    // when the region is exhausted. The padding is not lost: it is recycled into the small size
+    // classes, and later blocks which fit are taken from there (or from the large free list)
+    // before bumping, so stores of any alignment can be mixed.
+    pub(crate) unsafe fn alloc_layout(&self, layout: Layout) -> *mut u8 {
+        match self.try_alloc_layout(layout) {
+            Ok(ptr) => ptr,
+            Err(error) => {
+                console_log!("[WasmAllocator::alloc_layout()] ERROR: {}", error);
+                panic!();
+            }
+        }
+    }
+
+    pub(crate) unsafe fn try_alloc_layout(&self, layout: Layout) -> Result<*mut u8, CoreError> {
+        self.try_alloc_tagged(layout, "alloc_layout")
+    }
+
+    // Same as try_alloc_layout(), with a tag naming what the block is for (usually a type name).
+    // The tag is reported as the block's origin when its guard bytes are found corrupted.
+    pub(crate) unsafe fn try_alloc_tagged(
+        &self,
+        layout: Layout,
+        tag: &'static str,
+    ) -> Result<*mut u8, CoreError> {
+        let guarded_layout = Self::guarded_layout(layout);
+        let base = self.retry_on_oom(|| self.try_arena_block(guarded_layout))?;
+
+        self.arena_bytes.set(self.arena_bytes.get() + layout.size());
+        self.allocation_count.set(self.allocation_count.get() + 1);
+
+        let ptr = self.arm_guards(base, layout, tag);
+        self.trace(ptr, layout, tag);
+        Ok(ptr)
+    }
+
+    // Arena blocks reuse a free block when one fits: first a block of the smallest non empty size
+    // class fitting the request (recycled alignment gaps among them), then a large free block.
+    // Only then is the bump region used.
+    unsafe fn try_arena_block(&self, layout: Layout) -> Result<*mut u8, CoreError> {
+        if let Some(fitting_class) = Self::size_class(layout) {
+            for class in fitting_class..SIZE_CLASSES {
+                let block = self.small_free_lists[class].get();
+                if !block.is_null() {
+                    self.small_free_lists[class].set((*block).next);
+                    return Ok(block as *mut u8);
+                }
+            }
+        }
+
+        self.take_or_bump(layout)
+    }
+
+    unsafe fn try_bump(&self, layout: Layout) -> Result<*mut u8, CoreError> {
+        loop {
+            let tracking_ptr_usize = self.tracking_ptr.get() as usize;
+            let padding = tracking_ptr_usize.wrapping_neg() & (layout.align() - 1);
+            let required = padding
+                .checked_add(layout.size())
+                .ok_or(CoreError::InvalidLayout {
+                    size: layout.size(),
+                    align: layout.align(),
+                })?;
+            let available = self.allocation_size.get();
+
+            if required <= available {
+                let ptr = (tracking_ptr_usize + padding) as *mut u8;
+                self.recycle_gap(self.tracking_ptr.get(), padding);
+                self.tracking_ptr.set(ptr.add(layout.size()));
+                self.allocation_size.set(available - required);
+                return Ok(ptr);
+            }
+
+            // The loop runs again if the grown pages did not extend the current region.
+            let pages = (required - available).div_ceil(PAGE_SIZE);
+            self.try_internal_alloc(pages)?;
+        }
+    }
+
+    // Splits an alignment gap into the largest naturally aligned blocks it holds, and pushes them
+    // onto the matching small free lists. Blocks under the smallest size class are dropped.
+    unsafe fn recycle_gap(&self, ptr: *mut u8, size: usize) {
+        let mut offset = 0;
+
+        while offset < size {
+            let address = ptr as usize + offset;
+            let remaining = size - offset;
+
+            // Largest power of two both dividing the address and fitting the remaining bytes
+            let aligned = 1 << address.trailing_zeros().min(usize::BITS - 1);
+            let fitting = 1 << (usize::BITS - 1 - remaining.leading_zeros());
+            let block_size = aligned.min(fitting).min(MAX_SMALL_SIZE);
+
+            if block_size >= 1 << MIN_CLASS_SHIFT {
+                let class = (block_size.trailing_zeros() - MIN_CLASS_SHIFT) as usize;
+                self.push_small_block(class, ptr.add(offset));
+            }
+            offset += block_size;
+        }
+    }
+
+    // Carves a region of 'bytes' out of the bump arena for per frame allocations. Reserving again
+    // (for example to grow the region) recycles the previous region, and ends the current frame.
+    pub(crate) unsafe fn reserve_frame_arena(&self, bytes: usize) {
+        if let Err(error) = self.try_reserve_frame_arena(bytes) {
+            console_log!("[WasmAllocator::reserve_frame_arena()] ERROR: {}", error);
+            panic!();
+        }
+    }
+
+    pub(crate) unsafe fn try_reserve_frame_arena(&self, bytes: usize) -> Result<(), CoreError> {
+        let previous = self.frame_arena.region();
+        let region = self.try_reserve_region(bytes, "FrameArena")?;
+
+        // Ends the frame (poisoning it in debug builds) while the previous region is still ours
+        self.frame_arena.reset();
+        self.frame_arena.set_region(region, bytes);
+        self.release_region(previous);
+        Ok(())
+    }
+
+    pub(crate) fn frame_alloc<T: Copy>(&self, value: T) -> FramePtr<'_, T> {
+        match self.try_frame_alloc(value) {
+            Ok(frame_ptr) => frame_ptr,
+            Err(error) => {
+                console_log!("[WasmAllocator::frame_alloc()] ERROR: {}", error);
+                panic!();
+            }
+        }
+    }
+
+    // Fails when no frame arena was reserved, or when the frame has used it up.
+    pub(crate) fn try_frame_alloc<T: Copy>(&self, value: T) -> Result<FramePtr<'_, T>, CoreError> {
+        self.frame_arena.try_alloc(value)
+    }
+
+    // Frees every frame allocation at once, in O(1).
+    pub(crate) fn end_frame(&self) {
+        self.frame_arena.reset();
+    }
+
+    pub(crate) fn frame_arena(&self) -> &FrameArena {
+        &self.frame_arena
+    }
+
+    // Creates an empty pool of T, which takes its pages from this allocator as it grows.
+    pub(crate) fn pool<T>(&self) -> Pool<'_, T, B> {
+        Pool::new(self)
+    }
+
+    // Carves a region of 'bytes' out of the bump arena for LIFO allocations. Reserving again
+    // recycles the previous region, and releases every allocation and marker made from it.
+    pub(crate) unsafe fn reserve_stack_arena(&self, bytes: usize) {
+        if let Err(error) = self.try_reserve_stack_arena(bytes) {
+            console_log!("[WasmAllocator::reserve_stack_arena()] ERROR: {}", error);
+            panic!();
+        }
+    }
+
+    pub(crate) unsafe fn try_reserve_stack_arena(&self, bytes: usize) -> Result<(), CoreError> {
+        let previous = self.stack_arena.region();
+        let region = self.try_reserve_region(bytes, "StackArena")?;
+
+        self.stack_arena.set_region(region, bytes);
+        self.release_region(previous);
+        Ok(())
+    }
+
+    pub(crate) fn stack_alloc(&self, layout: Layout) -> *mut u8 {
+        match self.try_stack_alloc(layout) {
+            Ok(ptr) => ptr,
+            Err(error) => {
+                console_log!("[WasmAllocator::stack_alloc()] ERROR: {}", error);
+                panic!();
+            }
+        }
+    }
+
+    // Fails when no stack arena was reserved, or when it is used up.
+    pub(crate) fn try_stack_alloc(&self, layout: Layout) -> Result<*mut u8, CoreError> {
+        self.stack_arena.try_alloc_layout(layout)
+    }
+
+    // Captures the current stack top, see rollback().
+    pub(crate) fn marker(&self) -> StackMarker {
+        self.stack_arena.marker()
+    }
+
+    pub(crate) fn rollback(&self, marker: StackMarker) {
+        if let Err(rejected) = self.try_rollback(marker) {
+            console_log!("[WasmAllocator::rollback()] ERROR: {}", rejected.error);
+            panic!();
+        }
+    }
+
+    // Frees every stack allocation made after the marker was taken. Markers must be rolled back
+    // innermost first, anything else is rejected and hands the marker back.
+    pub(crate) fn try_rollback(&self, marker: StackMarker) -> Result<(), RejectedRollback> {
+        self.stack_arena.try_rollback(marker)
+    }
+
+    pub(crate) fn stack_arena(&self) -> &StackArena {
+        &self.stack_arena
+    }
+
+    // Takes a region for an arena from the bump arena. The arena counters treat a region as a
+    // single allocation.
+    unsafe fn try_reserve_region(
+        &self,
+        bytes: usize,
+        tag: &'static str,
+    ) -> Result<*mut u8, CoreError> {
+        let layout = Layout::from_size_align(bytes, 1 << MIN_CLASS_SHIFT).map_err(|_| {
+            CoreError::InvalidLayout {
+                size: bytes,
+                align: 1 << MIN_CLASS_SHIFT,
+            }
+        })?;
+        self.try_alloc_tagged(layout, tag)
+    }
+
+    // Returns a region taken by try_reserve_region() to the large free list, once its arena no
+    // longer uses it. A null region is ignored.
+    unsafe fn release_region(&self, region: (*mut u8, usize)) {
+        let (ptr, size) = region;
+        if ptr.is_null() {
+            return;
+        }
+
+        // Checked when the region was reserved
+        let layout = Layout::from_size_align_unchecked(size, 1 << MIN_CLASS_SHIFT);
+        self.trace_table.remove(ptr as usize);
+        let base = self.disarm_guards(ptr, layout);
+        self.push_large_block(base, Self::guarded_layout(layout).size());
+        self.arena_bytes.set(self.arena_bytes.get() - size);
+        self.allocation_count.set(self.allocation_count.get() - 1);
+    }
+
+    pub(crate) unsafe fn enable_tracing(&self, records: usize) {
+        if let Err(error) = self.try_enable_tracing(records) {
+            console_log!("[WasmAllocator::enable_tracing()] ERROR: {}", error);
+            panic!();
+        }
+    }
+
+    // Starts recording every allocation (size, alignment, tag, and the frame number advanced by
+    // end_frame()) until disable_tracing(). The record table holds up to 'records' live
+    // allocations, and is only reserved the first time tracing is enabled.
+    pub(crate) unsafe fn try_enable_tracing(&self, records: usize) -> Result<(), CoreError> {
+        if !self.trace_table.has_region() {
+            let invalid_layout = || CoreError::InvalidLayout {
+                size: records.saturating_mul(size_of::<TraceRecord>()),
+                align: align_of::<TraceRecord>(),
+            };
+            let capacity = TraceTable::table_capacity(records).ok_or_else(invalid_layout)?;
+            let layout = Layout::array::<TraceRecord>(capacity).map_err(|_| invalid_layout())?;
+            let region = self.try_alloc_tagged(layout, "TraceTable")?;
+            self.trace_table
+                .set_region(region as *mut TraceRecord, capacity);
+        }
+
+        self.trace_table.set_enabled(true);
+        Ok(())
+    }
+
+    // Stops recording new allocations. Frees of recorded allocations are still tracked.
+    pub(crate) fn disable_tracing(&self) {
+        self.trace_table.set_enabled(false);
+    }
+
+    // Sets the tag recorded for the following GlobalAlloc allocations, and returns the previous
+    // one so that it can be restored, for example around a level load.
+    pub(crate) fn set_trace_tag(&self, tag: &'static str) -> &'static str {
+        self.trace_tag.replace(tag)
+    }
+
+    // Live allocations recorded since tracing was enabled, grouped by tag.
+    pub(crate) fn trace_report(&self) -> TraceReport {
+        // Building the report allocates, which must not be recorded while the table is read.
+        let enabled = self.trace_table.is_enabled();
+        self.trace_table.set_enabled(false);
+        let report = self.trace_table.report();
+        self.trace_table.set_enabled(enabled);
+
+        report
+    }
+
+    fn trace(&self, ptr: *mut u8, layout: Layout, tag: &'static str) {
+        self.trace_table.insert(TraceRecord {
+            ptr: ptr as usize,
+            size: layout.size(),
+            align: layout.align(),
+            tag,
+            frame: self.frame_arena.frame(),
+        });
+    }
+
+    // Checks the guard bytes of every live block, and reports the first corruption found since
+    // the last check (including the ones found while freeing) with the block's origin.
+    #[cfg(feature = "allocator-debug")]
+    pub(crate) fn check_guards(&self) {
+        if let Err(error) = self.try_check_guards() {
+            console_log!("[WasmAllocator::check_guards()] ERROR: {}", error);
+            panic!();
+        }
+    }
+
+    #[cfg(feature = "allocator-debug")]
+    pub(crate) fn try_check_guards(&self) -> Result<(), CoreError> {
+        self.guards.check()
+    }
+
+    // With the 'allocator-debug' feature, every block is allocated with room for its guard
+    // bytes. Without it, these helpers leave blocks untouched.
+    #[cfg(feature = "allocator-debug")]
+    fn guarded_layout(layout: Layout) -> Layout {
+        GuardList::guarded_layout(layout)
+    }
+
+    #[cfg(not(feature = "allocator-debug"))]
+    fn guarded_layout(layout: Layout) -> Layout {
+        layout
+    }
+
+    #[cfg(feature = "allocator-debug")]
+    unsafe fn arm_guards(&self, base: *mut u8, layout: Layout, tag: &'static str) -> *mut u8 {
+        self.guards.arm(base, layout, tag)
+    }
+
+    #[cfg(not(feature = "allocator-debug"))]
+    unsafe fn arm_guards(&self, base: *mut u8, _layout: Layout, _tag: &'static str) -> *mut u8 {
+        base
+    }
+
+    #[cfg(feature = "allocator-debug")]
+    unsafe fn disarm_guards(&self, ptr: *mut u8, layout: Layout) -> *mut u8 {
+        self.guards.disarm(ptr, layout)
+    }
+
+    #[cfg(not(feature = "allocator-debug"))]
+    unsafe fn disarm_guards(&self, ptr: *mut u8, _layout: Layout) -> *mut u8 {
+        ptr
+    }
+
+    // Returns the size class index for a request, or None when it takes the large block path.
+    // Blocks of a size class are aligned to their size, which satisfies any alignment up to it.
+    fn size_class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align()).max(1 << MIN_CLASS_SHIFT);
+        if size > MAX_SMALL_SIZE {
+            return None;
+        }
+
+        let class_shift = size.next_power_of_two().trailing_zeros();
+        Some((class_shift - MIN_CLASS_SHIFT) as usize)
+    }
+
+    fn class_size(class: usize) -> usize {
+        1 << (class as u32 + MIN_CLASS_SHIFT)
+    }
+
+    // Large blocks are whole pages
+    fn large_layout(layout: Layout) -> Layout {
+        let size = layout.size().next_multiple_of(PAGE_SIZE);
+        let align = layout.align().max(PAGE_SIZE);
+
+        unsafe { Layout::from_size_align_unchecked(size, align) }
+    }
+
+    unsafe fn alloc_small(&self, class: usize) -> Result<*mut u8, CoreError> {
+        if self.small_free_lists[class].get().is_null() {
+            self.refill_class(class)?;
+        }
+
+        let block = self.small_free_lists[class].get();
+        self.small_free_lists[class].set((*block).next);
+        Ok(block as *mut u8)
+    }
+
+    fn span_size(class: usize) -> usize {
+        Self::class_size(class).max(SPAN_SIZE)
+    }
+
+    // Carves a fresh span, from the large free list or the bump arena, into blocks of the class
+    // size.
+    unsafe fn refill_class(&self, class: usize) -> Result<(), CoreError> {
+        let class_size = Self::class_size(class);
+        let span_size = Self::span_size(class);
+        let span = self.take_or_bump(Layout::from_size_align_unchecked(span_size, span_size))?;
+
+        for offset in (0..span_size).step_by(class_size).rev() {
+            self.push_small_block(class, span.add(offset));
+        }
+        Ok(())
+    }
+
+    unsafe fn push_small_block(&self, class: usize, ptr: *mut u8) {
+        let block = ptr as *mut FreeBlock;
+        block.write(FreeBlock {
+            size: Self::class_size(class),
+            next: self.small_free_lists[class].get(),
+        });
+        self.small_free_lists[class].set(block);
+    }
+
+    unsafe fn alloc_large(&self, layout: Layout) -> Result<*mut u8, CoreError> {
+        self.take_or_bump(layout)
+    }
+
+    // Reuses a block of the large free list when one fits, and bumps otherwise. When the bump
+    // region is too small as well, fully free spans are reclaimed from the size classes before
+    // memory is grown.
+    unsafe fn take_or_bump(&self, layout: Layout) -> Result<*mut u8, CoreError> {
+        let reused = self.take_large_block(layout);
+        if !reused.is_null() {
+            return Ok(reused);
+        }
+
+        if !self.bump_fits(layout) && self.reclaim_spans() {
+            let reused = self.take_large_block(layout);
+            if !reused.is_null() {
+                return Ok(reused);
+            }
+        }
+
+        self.try_bump(layout)
+    }
+
+    fn bump_fits(&self, layout: Layout) -> bool {
+        let padding = (self.tracking_ptr.get() as usize).wrapping_neg() & (layout.align() - 1);
+        match padding.checked_add(layout.size()) {
+            Some(required) => required <= self.allocation_size.get(),
+            None => false,
+        }
+    }
+
+    // Returns every span whose blocks are all free to the large free list, and reports whether
+    // any was. Sorted by address, the free blocks of a span are consecutive, and they cover the
+    // whole span when there are as many as the span holds.
+    unsafe fn reclaim_spans(&self) -> bool {
+        let mut reclaimed = false;
+
+        for class in 0..SIZE_CLASSES {
+            let span_size = Self::span_size(class);
+            let blocks_per_span = span_size / Self::class_size(class);
+
+            let mut block = Self::sort_blocks(self.small_free_lists[class].get());
+            let mut kept: *mut FreeBlock = null_mut();
+            let mut kept_tail: *mut FreeBlock = null_mut();
+
+            while !block.is_null() {
+                let span = (block as usize) & !(span_size - 1);
+
+                // The run of free blocks within the same span
+                let mut last = block;
+                let mut count = 1;
+                while !(*last).next.is_null() && ((*last).next as usize) & !(span_size - 1) == span
+                {
+                    last = (*last).next;
+                    count += 1;
+                }
+                let after = (*last).next;
+
+                if count == blocks_per_span {
+                    self.push_large_block(span as *mut u8, span_size);
+                    reclaimed = true;
+                } else {
+                    if kept_tail.is_null() {
+                        kept = block;
+                    } else {
+                        (*kept_tail).next = block;
+                    }
+                    kept_tail = last;
+                }
+
+                block = after;
+            }
+
+            if !kept_tail.is_null() {
+                (*kept_tail).next = null_mut();
+            }
+            self.small_free_lists[class].set(kept);
+        }
+
+        reclaimed
+    }
+
+    // Sorts a free list by address. Merge sort, which needs no memory of its own.
+    unsafe fn sort_blocks(list: *mut FreeBlock) -> *mut FreeBlock {
+        if list.is_null() || (*list).next.is_null() {
+            return list;
+        }
+
+        // Split the list in halves
+        let mut middle = list;
+        let mut fast = (*list).next;
+        while !fast.is_null() && !(*fast).next.is_null() {
+            middle = (*middle).next;
+            fast = (*(*fast).next).next;
+        }
+        let second = (*middle).next;
+        (*middle).next = null_mut();
+
+        let mut first = Self::sort_blocks(list);
+        let mut second = Self::sort_blocks(second);
+
+        let mut head: *mut FreeBlock = null_mut();
+        let mut tail: *mut FreeBlock = null_mut();
+        while !first.is_null() && !second.is_null() {
+            let lowest = if (first as usize) < (second as usize) {
+                let lowest = first;
+                first = (*first).next;
+                lowest
+            } else {
+                let lowest = second;
+                second = (*second).next;
+                lowest
+            };
+
+            if tail.is_null() {
+                head = lowest;
+            } else {
+                (*tail).next = lowest;
+            }
+            tail = lowest;
+        }
+
+        let rest = if first.is_null() { second } else { first };
+        if tail.is_null() {
+            return rest;
+        }
+        (*tail).next = rest;
+        head
+    }
+
+    // Resizes a live GlobalAlloc block without moving it, and returns false when it has to move.
+    // A block keeps its place when the new size still fits its size class (or its whole pages),
+    // and a large block grows in place over the free block right after it, or over the bump
+    // region when it is the last bump allocation.
+    unsafe fn try_realloc_in_place(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_layout: Layout,
+    ) -> bool {
+        // Guard bytes pin the block layout, so debug builds always move the block.
+        if cfg!(feature = "allocator-debug") {
+            return false;
+        }
+
+        let (granted, new_granted) = match (Self::size_class(layout), Self::size_class(new_layout))
+        {
+            (Some(class), Some(new_class)) if class == new_class => {
+                (Self::class_size(class), Self::class_size(class))
+            }
+            (None, None) => {
+                let size = Self::large_layout(layout).size();
+                let new_size = Self::large_layout(new_layout).size();
+
+                if new_size < size {
+                    self.push_large_block(ptr.add(new_size), size - new_size);
+                } else if new_size > size
+                    && !self.try_grow_large_in_place(ptr, size, new_size - size)
+                {
+                    return false;
+                }
+                (size, new_size)
+            }
+            _ => return false,
+        };
+
+        self.requested_bytes
+            .set(self.requested_bytes.get() - layout.size() + new_layout.size());
+        self.granted_bytes
+            .set(self.granted_bytes.get() - granted + new_granted);
+        true
+    }
+
+    // Extends the large block [ptr, ptr + size) by 'extra' bytes, if the bytes after it are free.
+    unsafe fn try_grow_large_in_place(&self, ptr: *mut u8, size: usize, extra: usize) -> bool {
+        let end = ptr.add(size);
+
+        // CASE #1: The next block is free, and large enough
+        let mut previous: *mut FreeBlock = null_mut();
+        let mut block = self.large_free_list.get();
+
+        while !block.is_null() {
+            let block_size = (*block).size;
+            let next = (*block).next;
+
+            if block as *mut u8 == end && block_size >= extra {
+                if previous.is_null() {
+                    self.large_free_list.set(next);
+                } else {
+                    (*previous).next = next;
+                }
+
+                self.push_large_block(end.add(extra), block_size - extra);
+                return true;
+            }
+
+            previous = block;
+            block = next;
+        }
+
+        // CASE #2: The block is the last bump allocation. The bump region is grown as needed,
+        // unless the new pages would not be contiguous with it.
+        if end != self.tracking_ptr.get() {
+            return false;
+        }
+
+        if extra > self.allocation_size.get() {
+            let pages = (extra - self.allocation_size.get()).div_ceil(PAGE_SIZE);
+            if self.try_internal_alloc(pages).is_err() || end != self.tracking_ptr.get() {
+                return false;
+            }
+        }
+
+        self.tracking_ptr.set(end.add(extra));
+        self.allocation_size.set(self.allocation_size.get() - extra);
+        true
+    }
+
+    // First fit search through the large free list. The block is split around the request, and
+    // the bytes before and after it are returned to the large free list.
+    unsafe fn take_large_block(&self, layout: Layout) -> *mut u8 {
+        let mut previous: *mut FreeBlock = null_mut();
+        let mut block = self.large_free_list.get();
+
+        while !block.is_null() {
+            let size = (*block).size;
+            let next = (*block).next;
+            let padding = (block as usize).wrapping_neg() & (layout.align() - 1);
+
+            if size >= padding && size - padding >= layout.size() {
+                if previous.is_null() {
+                    self.large_free_list.set(next);
+                } else {
+                    (*previous).next = next;
+                }
+
+                let ptr = (block as *mut u8).add(padding);
+                self.push_large_block(block as *mut u8, padding);
+                self.push_large_block(ptr.add(layout.size()), size - padding - layout.size());
+                return ptr;
+            }
+
+            previous = block;
+            block = next;
+        }
+
+        null_mut()
+    }
+
+    // Inserts a block in address order, merged with the free blocks right before and after it. A
+    // block ending where the bump region starts is merged into the bump region instead.
+    //
+    // Blocks too small (or too misaligned) to hold a FreeBlock header are dropped.
+    unsafe fn push_large_block(&self, ptr: *mut u8, size: usize) {
+        let padding = (ptr as usize).wrapping_neg() & (align_of::<FreeBlock>() - 1);
+        if size < padding + size_of::<FreeBlock>() {
+            return;
+        }
+
+        let start = ptr.add(padding);
+        let size = size - padding;
+
+        // Find the neighbours: 'previous' is the last block before 'start', and 'before' the one
+        // before it
+        let mut before: *mut FreeBlock = null_mut();
+        let mut previous: *mut FreeBlock = null_mut();
+        let mut next = self.large_free_list.get();
+        while !next.is_null() && (next as usize) < start as usize {
+            before = previous;
+            previous = next;
+            next = (*next).next;
+        }
+
+        let block = if !previous.is_null() && (previous as *mut u8).add((*previous).size) == start {
+            (*previous).size += size;
+            previous
+        } else {
+            let block = start as *mut FreeBlock;
+            block.write(FreeBlock { size, next });
+            if previous.is_null() {
+                self.large_free_list.set(block);
+            } else {
+                (*previous).next = block;
+            }
+            block
+        };
+
+        if (block as *mut u8).add((*block).size) == next as *mut u8 {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if (block as *mut u8).add((*block).size) == self.tracking_ptr.get() {
+            let link = if block == previous { before } else { previous };
+            if link.is_null() {
+                self.large_free_list.set((*block).next);
+            } else {
+                (*link).next = (*block).next;
+            }
+
+            self.tracking_ptr.set(block as *mut u8);
+            self.allocation_size
+                .set(self.allocation_size.get() + (*block).size);
+        }
     }
 }
 
 impl Default for WasmAllocator {
     fn default() -> Self {
-        // The starting pointer value where we can begin constructing objects
-        // This ignores the initial allocated pages created before our process starts...
-        let allocated_start_pointer = (WasmAllocator::memory_size() * PAGE_SIZE) as *mut u8;
+        WasmAllocator::new()
+    }
+}
 
-        WasmAllocator {
-            lead_ptr: allocated_start_pointer,
-            tracking_ptr: allocated_start_pointer,
-            allocation_size: 0,
+// Installable as the process allocator, see the 'global-allocator' feature in lib.rs.
+// Must never log: console_log!() allocates, and would re-enter the allocator.
+unsafe impl<B: MemoryBackend> GlobalAlloc for WasmAllocator<B> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let guarded_layout = Self::guarded_layout(layout);
+        let (result, granted) = match Self::size_class(guarded_layout) {
+            Some(class) => (
+                self.retry_on_oom(|| self.alloc_small(class)),
+                Self::class_size(class),
+            ),
+            None => {
+                let large_layout = Self::large_layout(guarded_layout);
+                (
+                    self.retry_on_oom(|| self.alloc_large(large_layout)),
+                    large_layout.size(),
+                )
+            }
+        };
+
+        match result {
+            Ok(base) => {
+                self.requested_bytes
+                    .set(self.requested_bytes.get() + layout.size());
+                self.granted_bytes.set(self.granted_bytes.get() + granted);
+                self.allocation_count.set(self.allocation_count.get() + 1);
+
+                let tag = self.trace_tag.get();
+                let ptr = self.arm_guards(base, layout, tag);
+                self.trace(ptr, layout, tag);
+                ptr
+            }
+            Err(_) => null_mut(),
         }
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        if self.try_realloc_in_place(ptr, layout, new_layout) {
+            self.trace_table.update_size(ptr as usize, new_size);
+            return ptr;
+        }
+
+        // Fallback: move the block
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.trace_table.remove(ptr as usize);
+        let base = self.disarm_guards(ptr, layout);
+        let guarded_layout = Self::guarded_layout(layout);
+
+        let granted = match Self::size_class(guarded_layout) {
+            Some(class) => {
+                self.push_small_block(class, base);
+                Self::class_size(class)
+            }
+            None => {
+                let large_layout = Self::large_layout(guarded_layout);
+                self.push_large_block(base, large_layout.size());
+                large_layout.size()
+            }
+        };
+
+        self.requested_bytes
+            .set(self.requested_bytes.get() - layout.size());
+        self.granted_bytes.set(self.granted_bytes.get() - granted);
+        self.allocation_count.set(self.allocation_count.get() - 1);
+    }
 }
 
-unsafe impl GlobalAlloc for WasmAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        console_log!("Layout size {}", layout.size());
-        null_mut()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_backend::SimulatedMemory;
+
+    type TestAllocator = WasmAllocator<SimulatedMemory>;
+
+    fn limited_allocator(pages: usize) -> TestAllocator {
+        WasmAllocator::with_backend(SimulatedMemory::with_limit(pages))
+    }
+
+    // A request taking exactly 'count' pages, guard bytes included.
+    fn pages(count: usize) -> Layout {
+        let guard_bytes =
+            TestAllocator::guarded_layout(Layout::from_size_align(0, 8).unwrap()).size();
+        Layout::from_size_align(count * PAGE_SIZE - guard_bytes, 8).unwrap()
+    }
+
+    // Allocates the whole memory one page at a time, frees it in 'order', and checks the pages
+    // merge back into a block large enough for a multi page request.
+    unsafe fn free_pages_and_merge(order: impl Fn(usize) -> usize) {
+        let allocator = limited_allocator(32);
+        let blocks: Vec<*mut u8> = (0..32).map(|_| allocator.alloc(pages(1))).collect();
+        assert!(blocks.iter().all(|block| !block.is_null()));
+        assert!(allocator.alloc(pages(1)).is_null());
+
+        for index in 0..32 {
+            allocator.dealloc(blocks[order(index)], pages(1));
+        }
+
+        assert_eq!(allocator.largest_free_block(), 32 * PAGE_SIZE);
+        assert!(!allocator.alloc(pages(4)).is_null());
+        assert_eq!(allocator.memory_size(), 32);
+    }
+
+    #[test]
+    fn merges_pages_freed_in_order() {
+        unsafe { free_pages_and_merge(|index| index) };
+    }
+
+    #[test]
+    fn merges_pages_freed_in_reverse() {
+        unsafe { free_pages_and_merge(|index| 31 - index) };
+    }
+
+    #[test]
+    fn merges_pages_freed_interleaved() {
+        // Even pages first, then the odd pages fill the holes between them
+        unsafe { free_pages_and_merge(|index| (index * 2 + index / 16) % 32) };
+    }
+
+    #[test]
+    fn reuses_a_freed_block_before_the_bump_region() {
+        unsafe {
+            let allocator = limited_allocator(8);
+            let first = allocator.alloc(pages(2));
+            let second = allocator.alloc(pages(2));
+
+            allocator.dealloc(first, pages(2));
+            assert_eq!(allocator.alloc(pages(1)), first);
+            assert_eq!(allocator.alloc(pages(1)), first.add(PAGE_SIZE));
+            assert_eq!(allocator.memory_size(), 4);
+
+            allocator.dealloc(second, pages(2));
+        }
+    }
+
+    #[test]
+    fn reclaims_free_spans_for_large_blocks() {
+        unsafe {
+            let small = Layout::from_size_align(16, 16).unwrap();
+            let class = TestAllocator::size_class(TestAllocator::guarded_layout(small)).unwrap();
+            let allocator = limited_allocator(2);
+
+            // One page of small blocks, then a large block over the second page
+            let blocks: Vec<*mut u8> = (0..PAGE_SIZE / TestAllocator::class_size(class))
+                .map(|_| allocator.alloc(small))
+                .collect();
+            assert!(blocks.iter().all(|block| !block.is_null()));
+            let large = allocator.alloc(pages(1));
+            assert!(!large.is_null());
+
+            for block in blocks {
+                allocator.dealloc(block, small);
+            }
+
+            // Memory cannot grow, so the page has to come back from the size class
+            assert!(!allocator.alloc(pages(1)).is_null());
+            assert!(allocator.small_free_lists[class].get().is_null());
+            assert_eq!(allocator.memory_size(), 2);
+        }
+    }
+
+    #[test]
+    fn rejects_an_invalid_region_size() {
+        let allocator = limited_allocator(2);
+        let result = unsafe { allocator.try_reserve_frame_arena(usize::MAX) };
+
+        assert!(matches!(result, Err(CoreError::InvalidLayout { .. })));
+    }
+
+    #[cfg(feature = "allocator-debug")]
+    #[test]
+    fn reports_the_trace_tag_as_guard_origin() {
+        unsafe {
+            let allocator = limited_allocator(2);
+            let layout = Layout::from_size_align(24, 8).unwrap();
+
+            allocator.set_trace_tag("LevelLoad");
+            let ptr = allocator.alloc(layout);
+            ptr.add(layout.size()).write(0);
+
+            match allocator.try_check_guards() {
+                Err(CoreError::GuardCorrupted { origin, .. }) => assert_eq!(origin, "LevelLoad"),
+                _ => panic!("overrun not detected"),
+            }
+        }
+    }
+
+    #[test]
+    fn serves_requests_up_to_half_a_page_from_size_classes() {
+        unsafe {
+            let allocator = limited_allocator(1);
+            let layout = Layout::from_size_align(20 * 1024, 8).unwrap();
+
+            // Two of them share the single page
+            assert!(!allocator.alloc(layout).is_null());
+            assert!(!allocator.alloc(layout).is_null());
+            assert_eq!(allocator.memory_size(), 1);
+        }
+    }
+
+    #[test]
+    fn reserving_the_frame_arena_again_keeps_the_free_list_intact() {
+        unsafe {
+            let allocator = limited_allocator(8);
+            allocator.reserve_frame_arena(1024);
+            assert_eq!(*allocator.frame_alloc(7u64), 7);
+            allocator.reserve_frame_arena(4096);
+
+            assert!(allocator.largest_free_block() <= 8 * PAGE_SIZE);
+
+            let layout = Layout::from_size_align(100_000, 8).unwrap();
+            let ptr = allocator.alloc(layout) as usize;
+            let memory_start = allocator.backend.page_ptr(0) as usize;
+            let memory_end = allocator.backend.page_ptr(allocator.memory_size()) as usize;
+            assert!(ptr >= memory_start && ptr + layout.size() <= memory_end);
+        }
+    }
+
+    #[test]
+    fn rejects_a_trace_table_too_large_to_lay_out() {
+        let allocator = limited_allocator(2);
+
+        for records in [usize::MAX, usize::MAX / 2 + 1, usize::MAX / 64] {
+            let result = unsafe { allocator.try_enable_tracing(records) };
+            assert!(matches!(result, Err(CoreError::InvalidLayout { .. })));
+        }
+
+        // No table was installed, so a sensible size still works afterwards
+        unsafe { allocator.try_enable_tracing(64).unwrap() };
+        assert!(allocator.trace_table.has_region());
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {}
+    // Debug builds always move the block, see try_realloc_in_place()
+    #[cfg(not(feature = "allocator-debug"))]
+    #[test]
+    fn grows_the_last_bump_allocation_in_place() {
+        unsafe {
+            let allocator = limited_allocator(4);
+            let ptr = allocator.alloc(pages(2));
+
+            assert_eq!(allocator.realloc(ptr, pages(2), pages(3).size()), ptr);
+            assert_eq!(allocator.memory_size(), 3);
+        }
+    }
+
+    #[cfg(not(feature = "allocator-debug"))]
+    #[test]
+    fn grows_over_the_adjacent_free_block_in_place() {
+        unsafe {
+            let allocator = limited_allocator(4);
+            let first = allocator.alloc(pages(1));
+            let second = allocator.alloc(pages(2));
+            let last = allocator.alloc(pages(1));
+
+            allocator.dealloc(second, pages(2));
+            assert_eq!(allocator.realloc(first, pages(1), pages(2).size()), first);
+
+            // The rest of the freed block is still free, and memory is full
+            assert_eq!(allocator.alloc(pages(1)), first.add(2 * PAGE_SIZE));
+            assert!(allocator.alloc(pages(1)).is_null());
+            assert_eq!(last, first.add(3 * PAGE_SIZE));
+        }
+    }
+
+    #[cfg(not(feature = "allocator-debug"))]
+    #[test]
+    fn shrinks_in_place_and_frees_the_tail() {
+        unsafe {
+            let allocator = limited_allocator(4);
+            let ptr = allocator.alloc(pages(3));
+            assert!(!allocator.alloc(pages(1)).is_null());
+
+            assert_eq!(allocator.realloc(ptr, pages(3), pages(1).size()), ptr);
+            assert_eq!(allocator.largest_free_block(), 2 * PAGE_SIZE);
+            assert_eq!(allocator.alloc(pages(2)), ptr.add(PAGE_SIZE));
+        }
+    }
+
+    #[cfg(not(feature = "allocator-debug"))]
+    #[test]
+    fn resizes_within_the_size_class_in_place() {
+        unsafe {
+            let allocator = limited_allocator(1);
+            let layout = Layout::from_size_align(40, 8).unwrap();
+            let ptr = allocator.alloc(layout);
+
+            assert_eq!(allocator.realloc(ptr, layout, 64), ptr);
+            assert_ne!(
+                allocator.realloc(ptr, Layout::from_size_align(64, 8).unwrap(), 65),
+                ptr
+            );
+        }
+    }
+
+    #[test]
+    fn memory_does_not_grow_while_locked() {
+        unsafe {
+            let allocator = limited_allocator(4);
+            let layout = Layout::from_size_align(64, 8).unwrap();
+
+            allocator.lock_growth();
+            assert!(matches!(
+                allocator.try_alloc_layout(layout),
+                Err(CoreError::GrowthLocked { views: 1 })
+            ));
+            assert!(allocator.alloc(pages(1)).is_null());
+            assert_eq!(allocator.memory_size(), 0);
+
+            allocator.unlock_growth();
+            assert!(allocator.try_alloc_layout(layout).is_ok());
+        }
+    }
 }
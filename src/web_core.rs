@@ -1,18 +1,32 @@
-use std::convert::TryFrom;
-use std::mem::{align_of, size_of};
+use std::alloc::Layout;
+use std::any::type_name;
+use std::mem::size_of;
+use std::ops::Deref;
 use std::ptr::{addr_of_mut, read, write};
 
 use super::{console_log, log};
+use crate::allocation_trace::TraceReport;
+use crate::error::CoreError;
 use crate::indexing::{Index, IndexType, UnsignedType};
-use crate::wasm_allocator::WasmAllocator;
+use crate::js_view::DenseLayout;
+use crate::key_set::{KeySet, Keys};
+use crate::membership::MembershipBits;
+use crate::memory_stats::{MemoryStats, StoreFootprint};
+use crate::wasm_allocator::{OomHandler, WasmAllocator};
 
+// Stores one value of type T per key. A zero sized T (a marker component) costs nothing beyond
+// the key bookkeeping: the data array occupies no bytes and the writes into it are elided.
+// TagVector offers the same membership API as a distinct type.
 pub(crate) struct KeyVector<T: Sized, I: UnsignedType, const N: usize> {
-    length: usize,
-    indices: [Index<I>; N],
+    keys: KeySet<I, N>,
     data: [T; N],
 }
 
+#[allow(dead_code)]
 impl<T: Sized, I: UnsignedType, const N: usize> KeyVector<T, I, N> {
+    // Fails the build for N == 0, or N > I::MAX_VALUE + 1
+    pub(crate) const CAPACITY_CHECK: () = KeySet::<I, N>::CAPACITY_CHECK;
+
     pub(crate) fn add(&mut self, key: usize)
     where
         T: Default,
@@ -23,160 +37,719 @@ impl<T: Sized, I: UnsignedType, const N: usize> KeyVector<T, I, N> {
             return;
         }
 
-        if key == (self.length + 1) {
-            self.add_equal_key_checked(key);
+        // Key already present
+        if self.location(key) != 0 {
             return;
         }
+
+        let location = self.insert_location(key);
+
+        let src: T = Default::default();
+        let dst = addr_of_mut!(self.data[location]);
+        unsafe {
+            write(dst, src);
+        }
     }
 
+    #[allow(clippy::needless_return)]
     pub(crate) fn find(&self, key: usize) -> &T
     where
         T: Default,
         Index<I>: IndexType,
     {
-        if key >= N {
-            return &self.data[0];
+        let key_location = self.location(key);
+        return &self.data[key_location];
+    }
+
+    #[allow(clippy::needless_return)]
+    pub(crate) fn find_mut(&mut self, key: usize) -> &mut T
+    where
+        T: Default,
+        Index<I>: IndexType,
+    {
+        let key_location = self.location(key);
+        return &mut self.data[key_location];
+    }
+
+    // Looks up the key a single time and returns a handle which can either read the stored value
+    // or insert a new one, without probing self.indices again.
+    //
+    // Returns None for keys outside of the valid range 1..N.
+    pub(crate) fn entry(&mut self, key: usize) -> Option<Entry<'_, T, I, N>>
+    where
+        Index<I>: IndexType,
+    {
+        if key == 0 || key >= N {
+            return None;
         }
 
-        if key <= self.length {
-            if key == self.indices[key].into() {
-                return &self.data[key];
-            }
-            return &self.data[0];
+        let location = self.location(key);
+
+        if location != 0 {
+            return Some(Entry::Occupied(OccupiedEntry {
+                store: self,
+                key,
+                location,
+            }));
         }
 
-        let key_location: usize = self.indices[key].into();
-        return &self.data[key_location];
+        Some(Entry::Vacant(VacantEntry { store: self, key }))
     }
 
-    pub(crate) fn find_mut(&mut self, key: usize) -> &mut T
+    pub(crate) fn remove(&mut self, key: usize) -> Option<T>
     where
-        T: Default,
         Index<I>: IndexType,
     {
-        if key >= N {
-            return &mut self.data[0];
+        let location = self.location(key);
+        if location == 0 {
+            return None;
         }
 
-        if key <= self.length {
-            if key == self.indices[key].into() {
-                return &mut self.data[key];
+        Some(self.remove_location(location))
+    }
+
+    // Removes every key for which the predicate returns false, dropping the removed values.
+    //
+    // The dense region is compacted in a single pass: each removal moves the last entry into the
+    // freed location, and that entry is visited next.
+    pub(crate) fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(usize, &mut T) -> bool,
+        Index<I>: IndexType,
+    {
+        let mut location = 1;
+
+        while location <= self.keys.len() {
+            let key = self.keys.key_at(location);
+
+            if f(key, &mut self.data[location]) {
+                location += 1;
+                continue;
             }
-            return &mut self.data[0];
+
+            drop(self.remove_location(location));
         }
+    }
 
-        let key_location: usize = self.indices[key].into();
-        return &mut self.data[key_location];
+    // Removes every key for which the predicate returns true, yielding (key, value) pairs.
+    //
+    // Dropping the iterator before it is exhausted still removes (and drops) the remaining
+    // matching values.
+    pub(crate) fn drain_filter<F>(&mut self, f: F) -> DrainFilter<'_, T, I, N, F>
+    where
+        F: FnMut(usize, &mut T) -> bool,
+        Index<I>: IndexType,
+    {
+        DrainFilter {
+            store: self,
+            location: 1,
+            pred: f,
+        }
     }
 
-    fn add_equal_key_checked(&mut self, key: usize)
+    // Moves the value stored under 'old' so it is stored under 'new' instead. The value is moved
+    // bitwise, it is never cloned.
+    //
+    // Returns false, leaving the store unchanged, when 'old' is not present or when 'new' is out of
+    // range or already present.
+    #[allow(clippy::needless_return)]
+    pub(crate) fn rekey(&mut self, old: usize, new: usize) -> bool
     where
-        T: Default,
         Index<I>: IndexType,
     {
-        if self.indices[key] != 0 {
-            return;
+        let old_location = self.location(old);
+        if old_location == 0 {
+            return false;
+        }
+
+        if old == new {
+            return true;
         }
 
-        let index_key = Self::usize_to_index(key);
-        self.indices[key] = index_key;
+        if !self.is_vacant(new) {
+            return false;
+        }
 
-        let src: T = Default::default();
-        let dst = addr_of_mut!(self.data[key]);
+        let value = self.remove_location(old_location);
+        let new_location = self.insert_location(new);
+
+        let dst = addr_of_mut!(self.data[new_location]);
         unsafe {
-            write(dst, src);
+            write(dst, value);
         }
+        return true;
+    }
 
-        self.length += 1;
+    // Moves the value stored under 'key' into another KeyVector, under the same key. The other
+    // store may use a different capacity or index width. The value is moved bitwise, it is never
+    // cloned.
+    //
+    // Returns false, leaving both stores unchanged, when the key is not present in this store or
+    // when it is out of range or already present in the other store.
+    #[allow(clippy::needless_return)]
+    pub(crate) fn transfer<J: UnsignedType, const M: usize>(
+        &mut self,
+        key: usize,
+        other: &mut KeyVector<T, J, M>,
+    ) -> bool
+    where
+        Index<I>: IndexType,
+        Index<J>: IndexType,
+    {
+        let location = self.location(key);
+        if location == 0 {
+            return false;
+        }
+
+        if !other.is_vacant(key) {
+            return false;
+        }
+
+        let value = self.remove_location(location);
+        let other_location = other.insert_location(key);
+
+        let dst = addr_of_mut!(other.data[other_location]);
+        unsafe {
+            write(dst, value);
+        }
+        return true;
     }
 
-    fn add_greater_key_checked(&mut self, key: usize)
+    pub(crate) fn len(&self) -> usize
     where
-        T: Default,
         Index<I>: IndexType,
     {
-        if self.indices[key] != 0 {
+        self.keys.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool
+    where
+        Index<I>: IndexType,
+    {
+        self.keys.is_empty()
+    }
+
+    pub(crate) fn contains(&self, key: usize) -> bool
+    where
+        Index<I>: IndexType,
+    {
+        self.keys.contains(key)
+    }
+
+    // Iterates the present keys in dense order.
+    pub(crate) fn keys(&self) -> Keys<'_, I, N>
+    where
+        Index<I>: IndexType,
+    {
+        self.keys.keys()
+    }
+
+    // Maintains a packed membership bitset alongside the indices, for fast queries across
+    // several stores. See membership::intersect() and membership::has_all().
+    pub(crate) fn enable_membership(&mut self)
+    where
+        Index<I>: IndexType,
+    {
+        self.keys.enable_membership();
+    }
+
+    pub(crate) fn disable_membership(&mut self)
+    where
+        Index<I>: IndexType,
+    {
+        self.keys.disable_membership();
+    }
+
+    // Returns None unless enable_membership() was called.
+    pub(crate) fn membership(&self) -> Option<&MembershipBits>
+    where
+        Index<I>: IndexType,
+    {
+        self.keys.membership()
+    }
+
+    // Iterates (key, value) pairs in dense order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (usize, &T)>
+    where
+        Index<I>: IndexType,
+    {
+        self.keys.keys().zip(self.data[1..=self.keys.len()].iter())
+    }
+
+    // Iterates (key, value) pairs in dense order.
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut T)>
+    where
+        Index<I>: IndexType,
+    {
+        let length = self.keys.len();
+        self.keys.keys().zip(self.data[1..=length].iter_mut())
+    }
+
+    // Where JS finds data[1..=length] and the matching dense key list, see DenseLayout.
+    pub(crate) fn dense_layout(&self) -> DenseLayout
+    where
+        Index<I>: IndexType,
+    {
+        DenseLayout {
+            data_offset: self.data.as_ptr().wrapping_add(1) as usize,
+            stride: size_of::<T>(),
+            length: self.keys.len(),
+            keys_offset: self.keys.dense_keys_ptr() as usize,
+            key_stride: size_of::<I>(),
+        }
+    }
+
+    fn location(&self, key: usize) -> usize
+    where
+        Index<I>: IndexType,
+    {
+        self.keys.location(key)
+    }
+
+    fn is_vacant(&self, key: usize) -> bool
+    where
+        Index<I>: IndexType,
+    {
+        self.keys.is_vacant(key)
+    }
+
+    // Reserves a data location for a key which is not yet present, moving the data of every key
+    // that had to move. The returned location is uninitialized: the caller *MUST* write a value
+    // into it.
+    fn insert_location(&mut self, key: usize) -> usize
+    where
+        Index<I>: IndexType,
+    {
+        let data = &mut self.data;
+        self.keys
+            .insert_location(key, |from, to| Self::relocate(data, from, to))
+    }
+
+    // Moves the value out of a location within the dense region, and fills the gap with the last
+    // entry so the region stays compact.
+    #[allow(clippy::needless_return)]
+    fn remove_location(&mut self, location: usize) -> T
+    where
+        Index<I>: IndexType,
+    {
+        let value = unsafe { read(&self.data[location]) };
+
+        let data = &mut self.data;
+        self.keys
+            .remove_location(location, |from, to| Self::relocate(data, from, to));
+
+        return value;
+    }
+
+    // Bitwise move between two data locations. The source location is left logically
+    // uninitialized.
+    fn relocate(data: &mut [T; N], from: usize, to: usize) {
+        if size_of::<T>() == 0 {
             return;
         }
 
-        let index_pointer: usize = self.indices[self.length + 1].into();
+        unsafe {
+            let src = read(&data[from]);
+            let dst = addr_of_mut!(data[to]);
+            write(dst, src);
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub(crate) enum Entry<'a, T: Sized, I: UnsignedType, const N: usize> {
+    Occupied(OccupiedEntry<'a, T, I, N>),
+    Vacant(VacantEntry<'a, T, I, N>),
+}
+
+#[allow(dead_code)]
+pub(crate) struct OccupiedEntry<'a, T: Sized, I: UnsignedType, const N: usize> {
+    store: &'a mut KeyVector<T, I, N>,
+    key: usize,
+    location: usize,
+}
+
+#[allow(dead_code)]
+pub(crate) struct VacantEntry<'a, T: Sized, I: UnsignedType, const N: usize> {
+    store: &'a mut KeyVector<T, I, N>,
+    key: usize,
+}
+
+#[allow(dead_code)]
+impl<'a, T: Sized, I: UnsignedType, const N: usize> Entry<'a, T, I, N>
+where
+    Index<I>: IndexType,
+{
+    pub(crate) fn key(&self) -> usize {
+        match self {
+            Entry::Occupied(entry) => entry.key,
+            Entry::Vacant(entry) => entry.key,
+        }
+    }
 
-        // BRANCH POSSIBILITY #1
-        // CONDITION: self.indices[self.length + 1] != 0
-        // *(Requires extra swapping logic)*
+    pub(crate) fn or_insert(self, default: T) -> &'a mut T {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
 
-        if index_pointer != 0 {
-            self.indices[self.length + 1] = Self::usize_to_index(self.length + 1);
-            self.indices[index_pointer] = Self::usize_to_index(key);
-            self.indices[key] = Self::usize_to_index(index_pointer);
+    pub(crate) fn or_insert_with<F: FnOnce() -> T>(self, default: F) -> &'a mut T {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
 
-            unsafe {
-                let src = read(&self.data[index_pointer]);
-                let dst = addr_of_mut!(self.data[self.length + 1]);
-                write(dst, src);
+    pub(crate) fn or_default(self) -> &'a mut T
+    where
+        T: Default,
+    {
+        self.or_insert_with(Default::default)
+    }
 
-                let src: T = Default::default();
-                let dst = addr_of_mut!(self.data[index_pointer]);
-                write(dst, src);
+    pub(crate) fn and_modify<F: FnOnce(&mut T)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
             }
-            self.length += 1;
-            return;
+            Entry::Vacant(entry) => Entry::Vacant(entry),
         }
+    }
+}
+
+#[allow(dead_code)]
+impl<'a, T: Sized, I: UnsignedType, const N: usize> OccupiedEntry<'a, T, I, N>
+where
+    Index<I>: IndexType,
+{
+    pub(crate) fn key(&self) -> usize {
+        self.key
+    }
 
-        // BRANCH POSSIBILITY #2
-        // CONDITION: self.indices[self.length + 1] == 0
-        // *(Free to place greater key directly at self.length + 1)*
+    pub(crate) fn get(&self) -> &T {
+        &self.store.data[self.location]
+    }
 
-        self.indices[self.length + 1] = Self::usize_to_index(key);
-        self.indices[key] = Self::usize_to_index(self.length + 1);
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        &mut self.store.data[self.location]
+    }
 
+    pub(crate) fn into_mut(self) -> &'a mut T {
+        &mut self.store.data[self.location]
+    }
+
+    // Replaces the stored value, returning the previous one.
+    pub(crate) fn insert(&mut self, value: T) -> T {
+        std::mem::replace(self.get_mut(), value)
+    }
+
+    pub(crate) fn remove(self) -> T {
+        self.store.remove_location(self.location)
+    }
+}
+
+#[allow(dead_code)]
+impl<'a, T: Sized, I: UnsignedType, const N: usize> VacantEntry<'a, T, I, N>
+where
+    Index<I>: IndexType,
+{
+    pub(crate) fn key(&self) -> usize {
+        self.key
+    }
+
+    pub(crate) fn insert(self, value: T) -> &'a mut T {
+        let location = self.store.insert_location(self.key);
+
+        let dst = addr_of_mut!(self.store.data[location]);
         unsafe {
-            let src: T = Default::default();
-            let dst = addr_of_mut!(self.data[self.length + 1]);
-            write(dst, src);
+            write(dst, value);
         }
-        self.length += 1;
+
+        &mut self.store.data[location]
+    }
+}
+
+#[allow(dead_code)]
+pub(crate) struct DrainFilter<'a, T: Sized, I: UnsignedType, const N: usize, F>
+where
+    F: FnMut(usize, &mut T) -> bool,
+    Index<I>: IndexType,
+{
+    store: &'a mut KeyVector<T, I, N>,
+    location: usize,
+    pred: F,
+}
+
+impl<'a, T: Sized, I: UnsignedType, const N: usize, F> Iterator for DrainFilter<'a, T, I, N, F>
+where
+    F: FnMut(usize, &mut T) -> bool,
+    Index<I>: IndexType,
+{
+    type Item = (usize, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.location <= self.store.keys.len() {
+            let key = self.store.keys.key_at(self.location);
+
+            if (self.pred)(key, &mut self.store.data[self.location]) {
+                // The last entry moves into self.location, so it is visited next.
+                return Some((key, self.store.remove_location(self.location)));
+            }
+
+            self.location += 1;
+        }
+
+        None
     }
+}
+
+impl<'a, T: Sized, I: UnsignedType, const N: usize, F> Drop for DrainFilter<'a, T, I, N, F>
+where
+    F: FnMut(usize, &mut T) -> bool,
+    Index<I>: IndexType,
+{
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
+
+// Stores no data, only which keys are present. Intended for marker components such as
+// 'Selected' or 'Visible'.
+#[allow(dead_code)]
+pub(crate) struct TagVector<I: UnsignedType, const N: usize> {
+    keys: KeySet<I, N>,
+}
 
-    fn usize_to_index(key: usize) -> Index<I>
+#[allow(dead_code)]
+impl<I: UnsignedType, const N: usize> TagVector<I, N> {
+    // Fails the build for N == 0, or N > I::MAX_VALUE + 1
+    pub(crate) const CAPACITY_CHECK: () = KeySet::<I, N>::CAPACITY_CHECK;
+}
+
+#[allow(dead_code)]
+impl<I: UnsignedType, const N: usize> TagVector<I, N>
+where
+    Index<I>: IndexType,
+{
+    pub(crate) fn add(&mut self, key: usize) {
+        if !self.keys.is_vacant(key) {
+            return;
+        }
+
+        self.keys.insert_location(key, |_, _| {});
+    }
+
+    #[allow(clippy::needless_return)]
+    pub(crate) fn remove(&mut self, key: usize) -> bool {
+        let location = self.keys.location(key);
+        if location == 0 {
+            return false;
+        }
+
+        self.keys.remove_location(location, |_, _| {});
+        return true;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub(crate) fn contains(&self, key: usize) -> bool {
+        self.keys.contains(key)
+    }
+
+    // Iterates the present keys in dense order.
+    pub(crate) fn keys(&self) -> Keys<'_, I, N> {
+        self.keys.keys()
+    }
+
+    // Maintains a packed membership bitset alongside the indices, for fast queries across
+    // several stores. See membership::intersect() and membership::has_all().
+    pub(crate) fn enable_membership(&mut self) {
+        self.keys.enable_membership();
+    }
+
+    pub(crate) fn disable_membership(&mut self) {
+        self.keys.disable_membership();
+    }
+
+    // Returns None unless enable_membership() was called.
+    pub(crate) fn membership(&self) -> Option<&MembershipBits> {
+        self.keys.membership()
+    }
+
+    // Only the dense key list, a TagVector holds no data.
+    pub(crate) fn dense_layout(&self) -> DenseLayout {
+        DenseLayout {
+            data_offset: 0,
+            stride: 0,
+            length: self.keys.len(),
+            keys_offset: self.keys.dense_keys_ptr() as usize,
+            key_stride: size_of::<I>(),
+        }
+    }
+
+    // Removes every key for which the predicate returns false.
+    pub(crate) fn retain<F>(&mut self, mut f: F)
     where
-        Index<I>: IndexType,
+        F: FnMut(usize) -> bool,
     {
-        let downcast_result = Index::<I>::try_from(key);
+        let mut location = 1;
+
+        while location <= self.keys.len() {
+            if f(self.keys.key_at(location)) {
+                location += 1;
+                continue;
+            }
+
+            self.keys.remove_location(location, |_, _| {});
+        }
+    }
+
+    // Removes every key for which the predicate returns true, yielding the removed keys.
+    //
+    // Dropping the iterator before it is exhausted still removes the remaining matching keys.
+    pub(crate) fn drain_filter<F>(&mut self, f: F) -> TagDrainFilter<'_, I, N, F>
+    where
+        F: FnMut(usize) -> bool,
+    {
+        TagDrainFilter {
+            store: self,
+            location: 1,
+            pred: f,
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub(crate) struct TagDrainFilter<'a, I: UnsignedType, const N: usize, F>
+where
+    F: FnMut(usize) -> bool,
+    Index<I>: IndexType,
+{
+    store: &'a mut TagVector<I, N>,
+    location: usize,
+    pred: F,
+}
+
+impl<'a, I: UnsignedType, const N: usize, F> Iterator for TagDrainFilter<'a, I, N, F>
+where
+    F: FnMut(usize) -> bool,
+    Index<I>: IndexType,
+{
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.location <= self.store.keys.len() {
+            let key = self.store.keys.key_at(self.location);
+
+            if (self.pred)(key) {
+                // The last key moves into self.location, so it is visited next.
+                self.store.keys.remove_location(self.location, |_, _| {});
+                return Some(key);
+            }
+
+            self.location += 1;
+        }
+
+        None
+    }
+}
+
+impl<'a, I: UnsignedType, const N: usize, F> Drop for TagDrainFilter<'a, I, N, F>
+where
+    F: FnMut(usize) -> bool,
+    Index<I>: IndexType,
+{
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
+
+// The allocator a WebCore reserves its stores from. With the 'global-allocator' feature, it is
+// the #[global_allocator] itself, so stores and every Vec, String and Box share one allocator.
+enum CoreAllocator {
+    Owned(WasmAllocator),
+    #[cfg(all(feature = "global-allocator", target_arch = "wasm32"))]
+    Global(&'static WasmAllocator),
+}
 
-        // Match statement below can be removed once #[derive(Debug)] is correctly implemented for
-        // enums.
-        //
-        // Replace above with:
-        // let result = Index::<I>::try_from(key).expect("Error: {:?}");
+impl Deref for CoreAllocator {
+    type Target = WasmAllocator;
 
-        if let Ok(valid_index) = downcast_result {
-            // TODO: Remove console log
-            console_log!("Success: {:?}", valid_index);
-            return valid_index;
-        } else {
-            console_log!("Error: Usize bad downcast");
-            panic!();
+    fn deref(&self) -> &WasmAllocator {
+        match self {
+            CoreAllocator::Owned(wasm_allocator) => wasm_allocator,
+            #[cfg(all(feature = "global-allocator", target_arch = "wasm32"))]
+            CoreAllocator::Global(wasm_allocator) => wasm_allocator,
         }
     }
 }
 
 pub(super) struct WebCore {
-    wasm_allocator: WasmAllocator,
+    wasm_allocator: CoreAllocator,
+    stores: Vec<StoreFootprint>,
 }
 
+#[allow(dead_code)]
 impl WebCore {
     pub(super) fn new() -> Self {
-        let mut wasm_allocator = WasmAllocator {
-            ..Default::default()
-        };
+        match Self::try_new() {
+            Ok(webcore) => webcore,
+            Err(error) => {
+                console_log!("[WebCore::new()] ERROR: {}", error);
+                panic!();
+            }
+        }
+    }
+
+    // Shares the #[global_allocator] when the 'global-allocator' feature is enabled, and owns a
+    // WasmAllocator otherwise.
+    pub(super) fn try_new() -> Result<Self, CoreError> {
+        #[cfg(all(feature = "global-allocator", target_arch = "wasm32"))]
+        return Self::try_build(CoreAllocator::Global(&super::ALLOCATOR));
 
+        #[cfg(not(all(feature = "global-allocator", target_arch = "wasm32")))]
+        return Self::try_with_allocator(WasmAllocator::default());
+    }
+
+    // Builds on a preconfigured allocator, for example one limited to a few simulated pages.
+    pub(super) fn try_with_allocator(wasm_allocator: WasmAllocator) -> Result<Self, CoreError> {
+        Self::try_build(CoreAllocator::Owned(wasm_allocator))
+    }
+
+    fn try_build(wasm_allocator: CoreAllocator) -> Result<Self, CoreError> {
         unsafe {
-            wasm_allocator.internal_alloc(2);
+            wasm_allocator.try_internal_alloc(2)?;
         }
         wasm_allocator.debug_allocation_size();
 
-        WebCore { wasm_allocator }
+        Ok(WebCore {
+            wasm_allocator,
+            stores: Vec::new(),
+        })
+    }
+
+    pub(super) fn addkeyvec<T, I: UnsignedType, const N: usize>(
+        &mut self,
+    ) -> *mut KeyVector<T, I, N>
+    where
+        T: Default,
+        Index<I>: IndexType,
+    {
+        match self.try_addkeyvec::<T, I, N>() {
+            Ok(casted_ptr) => casted_ptr,
+            Err(error) => {
+                console_log!("[WebCore::addkeyvec()] ERROR: {}", error);
+                panic!();
+            }
+        }
     }
 
     // Until Rust permits 'Placement New' logic, we have to initialize the KeyVector by directly
@@ -184,77 +757,28 @@ impl WebCore {
     // Once Placement New is possible, we can ideally separate the KeyVector back into its own
     // module (we currently need it placed here to access the private fields - so we can write
     // bytes to these private fields).
-    pub(super) fn addkeyvec<T: Sized, I: UnsignedType, const N: usize>(
+    pub(super) fn try_addkeyvec<T, I: UnsignedType, const N: usize>(
         &mut self,
-    ) -> *mut KeyVector<T, I, N>
+    ) -> Result<*mut KeyVector<T, I, N>, CoreError>
     where
         T: Default,
         Index<I>: IndexType,
     {
-        let tracking_ptr_usize = self.wasm_allocator.tracking_ptr as usize;
-
-        // This check fills the role of a runtime assert that N != 0 which ideally would be placed
-        // as a 'static_assert' like in C++.
-        // It is possible that we can use const generics to handle these checks at compile time
-        // once it stabilizes.
-        if N == 0 {
-            console_log!("[KeyVector::new()] ERROR: N == 0");
-            panic!();
-        }
-
-        // This check fills the role of a runtime assert that N <= I::MAX_VALUE + 1 which ideally would be placed
-        // as a 'static_assert' like in C++.
-        // It is possible that we can use const generics to handle these checks at compile time
-        // once it stabilizes.
-        if N > (I::MAX_VALUE + 1) {
-            console_log!("[KeyVector::new()] ERROR: N > Index::MAX_VALUE");
-            panic!();
-        }
-
-        // This panic is avoidable. By adding padded bytes necessary to obtain a suitable aligned
-        // pointer
-        if (tracking_ptr_usize % align_of::<KeyVector<T, I, N>>()) != 0 {
-            console_log!("[WebCore::addkeyvec()] ERROR: Found unaligned tracking pointer");
-            panic!();
-        }
-        if self.wasm_allocator.allocation_size < size_of::<KeyVector<T, I, N>>() {
-            console_log!("[WebCore::addkeyvec()] ERROR: Allocation not large enough!");
-            console_log!(
-                "[WebCore::addkeyvec()] Allocation Size: {}",
-                self.wasm_allocator.allocation_size
-            );
-            console_log!(
-                "[WebCore::addkeyvec()] KeyVector Size: {}",
-                size_of::<KeyVector<T, I, N>>()
-            );
-            panic!();
-        }
+        // Rejected at compile time: N == 0, or N > I::MAX_VALUE + 1
+        let () = KeyVector::<T, I, N>::CAPACITY_CHECK;
+
+        let casted_ptr = self.try_reserve::<KeyVector<T, I, N>>(|store| unsafe {
+            (*(store as *const KeyVector<T, I, N>)).dense_layout()
+        })?;
+
         // At this stage:
-        // tracking_ptr is suitably aligned to be converted into *mut KeyVector<T, I, N>
-        let casted_ptr = self.wasm_allocator.tracking_ptr as *mut KeyVector<T, I, N>;
+        // casted_ptr is suitably aligned, and no other store overlaps its bytes
 
-        // Because placement new is not available, we initialize the field addresses of
-        // the first two variables (length, and indices set to ZERO.)
+        // Because placement new is not available, we initialize the key bookkeeping (length, and
+        // indices set to ZERO.)
 
         unsafe {
-            addr_of_mut!((*casted_ptr).length).write_bytes(0, 1);
-            // WE *MUST* CONFIRM THIS ZEROS THE ENTIRE ARRAY!!!
-            addr_of_mut!((*casted_ptr).indices).write_bytes(0, 1);
-
-            // This confirms that all values within the array [Index<I>; N] are cleared to zero.
-            // The entire array [Index<I>; N] is cycled
-            // Each value is tested against zero.
-            //
-            for i in 0..(*casted_ptr).indices.len() {
-                if (*casted_ptr).indices[i] != 0 {
-                    console_log!(
-                        "Invalid zeroing!!! I: {:?}, Value: {:?}",
-                        i,
-                        (*casted_ptr).indices[i].0
-                    );
-                    panic!();
-                }
-            }
+            KeySet::init(addr_of_mut!((*casted_ptr).keys));
 
             // Set zero element to a default value
             let src: T = Default::default();
@@ -262,11 +786,383 @@ impl WebCore {
             std::ptr::write(dst, src);
         }
 
-        // TODO: Subtract byte size from wasm_allocator.allocation_size
+        // TODO: Return *mut KeyVector<T, I, N> (or consider RefCell or something?)
+        Ok(casted_ptr)
+    }
 
-        // TODO: Move tracking pointer by byte size
+    // Creates a store for marker components, which only tracks key membership.
+    pub(super) fn addtagvec<I: UnsignedType, const N: usize>(&mut self) -> *mut TagVector<I, N>
+    where
+        Index<I>: IndexType,
+    {
+        match self.try_addtagvec::<I, N>() {
+            Ok(casted_ptr) => casted_ptr,
+            Err(error) => {
+                console_log!("[WebCore::addtagvec()] ERROR: {}", error);
+                panic!();
+            }
+        }
+    }
 
-        // TODO: Return *mut KeyVector<T, I, N> (or consider RefCell or something?)
-        return casted_ptr;
+    pub(super) fn try_addtagvec<I: UnsignedType, const N: usize>(
+        &mut self,
+    ) -> Result<*mut TagVector<I, N>, CoreError>
+    where
+        Index<I>: IndexType,
+    {
+        // Rejected at compile time: N == 0, or N > I::MAX_VALUE + 1
+        let () = TagVector::<I, N>::CAPACITY_CHECK;
+
+        let casted_ptr = self.try_reserve::<TagVector<I, N>>(|store| unsafe {
+            (*(store as *const TagVector<I, N>)).dense_layout()
+        })?;
+
+        unsafe {
+            KeySet::init(addr_of_mut!((*casted_ptr).keys));
+        }
+
+        Ok(casted_ptr)
+    }
+
+    // See WasmAllocator::lock_growth()
+    pub(super) fn lock_growth(&self) {
+        self.wasm_allocator.lock_growth();
+    }
+
+    pub(super) fn unlock_growth(&self) {
+        self.wasm_allocator.unlock_growth();
+    }
+
+    // See WasmAllocator::set_oom_handler()
+    pub(super) fn set_oom_handler(&self, oom_handler: Option<OomHandler>) {
+        self.wasm_allocator.set_oom_handler(oom_handler);
+    }
+
+    // See WasmAllocator::end_frame()
+    pub(super) fn end_frame(&self) {
+        self.wasm_allocator.end_frame();
+    }
+
+    // See WasmAllocator::try_enable_tracing()
+    pub(super) fn try_enable_tracing(&self, records: usize) -> Result<(), CoreError> {
+        unsafe { self.wasm_allocator.try_enable_tracing(records) }
+    }
+
+    pub(super) fn disable_tracing(&self) {
+        self.wasm_allocator.disable_tracing();
+    }
+
+    pub(super) fn trace_report(&self) -> TraceReport {
+        self.wasm_allocator.trace_report()
+    }
+
+    #[cfg(feature = "allocator-debug")]
+    pub(super) fn try_check_guards(&self) -> Result<(), CoreError> {
+        self.wasm_allocator.try_check_guards()
+    }
+
+    // Dense layout of a store, numbered in creation order (as in MemoryStats).
+    pub(super) fn try_dense_layout(&self, store: usize) -> Result<DenseLayout, CoreError> {
+        match self.stores.get(store) {
+            Some(footprint) => Ok((footprint.dense_layout)(footprint.location)),
+            None => Err(CoreError::UnknownStore { store }),
+        }
+    }
+
+    pub(super) fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            stores: self.stores.clone(),
+            ..self.wasm_allocator.memory_stats()
+        }
+    }
+
+    // Reserves space for constructing an S in place, from the allocator's bump arena.
+    // 'dense_layout' reads the DenseLayout of the store once it is initialized.
+    fn try_reserve<S>(
+        &mut self,
+        dense_layout: fn(*const u8) -> DenseLayout,
+    ) -> Result<*mut S, CoreError> {
+        let ptr = unsafe {
+            self.wasm_allocator
+                .try_alloc_tagged(Layout::new::<S>(), type_name::<S>())?
+        };
+
+        self.stores.push(StoreFootprint {
+            type_name: type_name::<S>(),
+            bytes: size_of::<S>(),
+            location: ptr,
+            dense_layout,
+        });
+        Ok(ptr as *mut S)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::memory_backend::SimulatedMemory;
+
+    fn limited_core(pages: usize) -> Result<WebCore, CoreError> {
+        WebCore::try_with_allocator(WasmAllocator::with_backend(SimulatedMemory::with_limit(
+            pages,
+        )))
+    }
+
+    #[test]
+    fn builds_on_a_limited_allocator() {
+        let mut webcore = limited_core(16).unwrap();
+        let store = webcore.try_addkeyvec::<u32, u8, 256>().unwrap();
+
+        assert!(!store.is_null());
+        assert_eq!(webcore.memory_stats().store_count(), 1);
+    }
+
+    #[test]
+    fn fails_at_the_page_limit() {
+        // WebCore grows memory by two pages up front
+        assert!(matches!(
+            limited_core(1),
+            Err(CoreError::OutOfMemory { .. })
+        ));
+
+        let mut webcore = limited_core(2).unwrap();
+        assert!(matches!(
+            webcore.try_addkeyvec::<[u64; 32], u16, 1024>(),
+            Err(CoreError::OutOfMemory { .. })
+        ));
+
+        // A store which fits is still accepted afterwards
+        assert!(webcore.try_addkeyvec::<u32, u8, 256>().is_ok());
+    }
+
+    #[test]
+    fn key_vector_round_trip() {
+        let mut webcore = limited_core(16).unwrap();
+        let store = unsafe { &mut *webcore.try_addkeyvec::<u32, u8, 256>().unwrap() };
+
+        for key in [3, 7, 200] {
+            store.add(key);
+            *store.find_mut(key) = key as u32 * 10;
+        }
+        assert_eq!(store.len(), 3);
+        assert_eq!(*store.find(7), 70);
+
+        assert_eq!(store.remove(7), Some(70));
+        assert_eq!(store.remove(7), None);
+        assert!(!store.contains(7));
+
+        // Removing swaps the last element into the hole, the other keys keep their values
+        assert_eq!(store.len(), 2);
+        assert_eq!(*store.find(3), 30);
+        assert_eq!(*store.find(200), 2000);
+
+        store.add(7);
+        assert_eq!(*store.find(7), 0);
+    }
+
+    // Fills keys 1..=count with key * 10.
+    fn filled_store(webcore: &mut WebCore, count: usize) -> &mut KeyVector<u32, u8, 16> {
+        let store = unsafe { &mut *webcore.try_addkeyvec::<u32, u8, 16>().unwrap() };
+        for key in 1..=count {
+            store.add(key);
+            *store.find_mut(key) = key as u32 * 10;
+        }
+        store
+    }
+
+    // Checks the store holds exactly the keys of 'expected' (with their key * 10 value), and that
+    // the sparse and dense halves of the indices agree.
+    fn assert_consistent<I: UnsignedType, const N: usize>(
+        store: &KeyVector<u32, I, N>,
+        expected: &[usize],
+    ) where
+        Index<I>: IndexType,
+    {
+        assert_eq!(store.len(), expected.len());
+
+        for key in 1..N {
+            assert_eq!(store.contains(key), expected.contains(&key), "key {}", key);
+        }
+
+        for &key in expected {
+            let location = store.location(key);
+            assert!(location >= 1 && location <= store.len());
+            assert_eq!(store.keys.key_at(location), key);
+            assert_eq!(*store.find(key), key as u32 * 10);
+        }
+
+        let mut keys: Vec<usize> = store.keys().collect();
+        keys.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn retain_removes_every_element() {
+        let mut webcore = limited_core(16).unwrap();
+        let store = filled_store(&mut webcore, 10);
+
+        store.retain(|_, _| false);
+        assert_consistent(store, &[]);
+
+        store.add(4);
+        *store.find_mut(4) = 40;
+        assert_consistent(store, &[4]);
+    }
+
+    #[test]
+    fn retain_removes_the_last_dense_element() {
+        let mut webcore = limited_core(16).unwrap();
+        let store = filled_store(&mut webcore, 5);
+
+        store.retain(|key, _| key != 5);
+        assert_consistent(store, &[1, 2, 3, 4]);
+
+        store.retain(|key, value| key % 2 == 0 && *value == key as u32 * 10);
+        assert_consistent(store, &[2, 4]);
+    }
+
+    #[test]
+    fn drain_filter_removes_every_element() {
+        let mut webcore = limited_core(16).unwrap();
+        let store = filled_store(&mut webcore, 10);
+
+        let mut drained: Vec<(usize, u32)> = store.drain_filter(|_, _| true).collect();
+        drained.sort();
+        assert_eq!(
+            drained,
+            (1..=10)
+                .map(|key| (key, key as u32 * 10))
+                .collect::<Vec<_>>()
+        );
+        assert_consistent(store, &[]);
+    }
+
+    #[test]
+    fn drain_filter_removes_the_last_dense_element() {
+        let mut webcore = limited_core(16).unwrap();
+        let store = filled_store(&mut webcore, 5);
+
+        let drained: Vec<(usize, u32)> = store.drain_filter(|key, _| key == 5).collect();
+        assert_eq!(drained, [(5, 50)]);
+        assert_consistent(store, &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn dropped_drain_filter_removes_the_rest() {
+        let mut webcore = limited_core(16).unwrap();
+        let store = filled_store(&mut webcore, 10);
+
+        {
+            let mut drain = store.drain_filter(|key, _| key % 2 == 1);
+            let (key, value) = drain.next().unwrap();
+            assert_eq!(value, key as u32 * 10);
+        }
+        assert_consistent(store, &[2, 4, 6, 8, 10]);
+    }
+
+    #[test]
+    fn removed_values_are_dropped_once() {
+        let shared = Rc::new(());
+        let mut webcore = limited_core(16).unwrap();
+        let store = unsafe { &mut *webcore.try_addkeyvec::<Rc<()>, u8, 16>().unwrap() };
+        for key in 1..=10 {
+            store.add(key);
+            *store.find_mut(key) = shared.clone();
+        }
+        assert_eq!(Rc::strong_count(&shared), 11);
+
+        store.retain(|key, _| key > 3);
+        assert_eq!(Rc::strong_count(&shared), 8);
+
+        // Keys 4, 6, 8 and 10, one of them yielded and the rest removed by the drop
+        drop(store.drain_filter(|key, _| key % 2 == 0).next());
+        assert_eq!(Rc::strong_count(&shared), 4);
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn rekey_moves_the_value() {
+        let mut webcore = limited_core(16).unwrap();
+        let store = filled_store(&mut webcore, 3);
+        // Stored under 2, checked under 12 by assert_consistent()
+        *store.find_mut(2) = 120;
+
+        assert!(store.rekey(2, 12));
+        assert_consistent(store, &[1, 3, 12]);
+    }
+
+    #[test]
+    fn rekey_to_itself_keeps_the_value() {
+        let mut webcore = limited_core(16).unwrap();
+        let store = filled_store(&mut webcore, 3);
+
+        assert!(store.rekey(2, 2));
+        assert_consistent(store, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn rekey_is_rejected_without_changes() {
+        let mut webcore = limited_core(16).unwrap();
+        let store = filled_store(&mut webcore, 3);
+
+        // 'new' already present
+        assert!(!store.rekey(1, 3));
+        // 'new' out of range
+        assert!(!store.rekey(1, 16));
+        assert!(!store.rekey(1, 1000));
+        // 'old' not present
+        assert!(!store.rekey(7, 8));
+
+        assert_consistent(store, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn transfer_between_different_stores() {
+        let mut webcore = limited_core(16).unwrap();
+        let large = unsafe { &mut *webcore.try_addkeyvec::<u32, u16, 64>().unwrap() };
+        let small = filled_store(&mut webcore, 3);
+
+        for key in [2, 40] {
+            large.add(key);
+            *large.find_mut(key) = key as u32 * 10;
+        }
+
+        // Key 40 is outside the capacity of the small store
+        assert!(!large.transfer(40, small));
+        assert_consistent(large, &[2, 40]);
+        assert_consistent(small, &[1, 2, 3]);
+
+        // Key 2 is already present in the small store
+        assert!(!large.transfer(2, small));
+        assert_consistent(large, &[2, 40]);
+
+        assert!(small.transfer(3, large));
+        assert_consistent(small, &[1, 2]);
+        assert_consistent(large, &[2, 3, 40]);
+
+        // Key 3 is not present anymore
+        assert!(!small.transfer(3, large));
+    }
+
+    #[repr(align(4096))]
+    #[derive(Default)]
+    struct PageAligned;
+
+    #[test]
+    fn alignment_gaps_are_handed_back_out() {
+        let mut webcore = limited_core(16).unwrap();
+
+        let first = webcore.try_addkeyvec::<u32, u8, 16>().unwrap() as usize;
+        let aligned = webcore.try_addkeyvec::<PageAligned, u8, 4>().unwrap() as usize;
+        assert!(
+            aligned - first >= 4096 / 2,
+            "expected a gap before the aligned store"
+        );
+
+        // Fits in the gap left before the aligned store
+        let reused = webcore.try_addtagvec::<u8, 16>().unwrap() as usize;
+        assert!(reused > first && reused < aligned);
     }
 }